@@ -0,0 +1,16 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(header) = std::str::from_utf8(data) {
+        if let Ok(mailboxes) = simplemm::parse_mail::parse_address_list(header) {
+            for mailbox in mailboxes {
+                // an address taken from a list has to parse back to itself
+                let reparsed = simplemm::parse_mail::parse_address_list(&mailbox.address)
+                    .expect("address does not parse again");
+                assert_eq!(reparsed.len(), 1);
+                assert_eq!(reparsed[0].address, mailbox.address);
+            }
+        }
+    }
+});
@@ -1,7 +1,7 @@
 CREATE TABLE mailing_lists (
-  id INTEGER NOT NULL PRIMARY KEY AUTO_INCREMENT, 
-  title VARCHAR(100) NOT NULL, 
-  email VARCHAR(50) NOT NULL, 
+  id INTEGER NOT NULL PRIMARY KEY AUTO_INCREMENT,
+  title VARCHAR(100) NOT NULL,
+  email VARCHAR(50) NOT NULL,
   enabled BOOLEAN NOT NULL DEFAULT true,
   language VARCHAR(2) NOT NULL DEFAULT 'EN'
 );
@@ -0,0 +1,8 @@
+ALTER TABLE mailing_lists
+  ADD COLUMN archive_url VARCHAR(255) NULL,
+  ADD COLUMN help_url VARCHAR(255) NULL,
+  ADD COLUMN unsubscribe_url VARCHAR(255) NULL;
+
+ALTER TABLE subscriptions
+  MODIFY timestamp TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  ADD COLUMN action ENUM('subscribe', 'unsubscribe') NOT NULL DEFAULT 'subscribe';
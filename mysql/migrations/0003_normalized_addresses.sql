@@ -0,0 +1,21 @@
+ALTER TABLE mailing_lists
+  MODIFY email VARCHAR(254) NOT NULL,
+  ADD COLUMN fold_case BOOLEAN NOT NULL DEFAULT true,
+  ADD COLUMN strip_subaddress BOOLEAN NOT NULL DEFAULT false;
+
+-- the code normalizes addresses, the database compares them exactly
+ALTER TABLE users
+  MODIFY email VARCHAR(254) CHARACTER SET utf8mb4 COLLATE utf8mb4_bin NOT NULL,
+  ADD COLUMN original_email VARCHAR(254) NOT NULL DEFAULT '';
+
+ALTER TABLE subscriptions
+  MODIFY email VARCHAR(254) CHARACTER SET utf8mb4 COLLATE utf8mb4_bin NOT NULL,
+  ADD COLUMN original_email VARCHAR(254) NOT NULL DEFAULT '';
+
+-- existing lists fold case, of members differing in case only one is kept
+DELETE u FROM users u
+  JOIN users v ON v.list_id = u.list_id AND LOWER(v.email) = LOWER(u.email) AND v.email < u.email;
+
+UPDATE users SET original_email = email, email = LOWER(email);
+
+UPDATE subscriptions SET original_email = email, email = LOWER(email);
@@ -0,0 +1,12 @@
+ALTER TABLE subscriptions
+  ADD COLUMN confirmation_sent TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP;
+
+UPDATE subscriptions SET confirmation_sent = timestamp;
+
+-- of several pending requests for an address only the newest is kept
+DELETE s FROM subscriptions s
+  JOIN subscriptions t ON t.list_id = s.list_id AND t.email = s.email
+    AND (t.timestamp > s.timestamp OR (t.timestamp = s.timestamp AND t.uuid > s.uuid));
+
+ALTER TABLE subscriptions
+  ADD UNIQUE INDEX subscriptions_list_id_email (list_id, email);
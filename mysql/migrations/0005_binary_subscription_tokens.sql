@@ -0,0 +1,3 @@
+-- tokens were written in their textual form, cut to the first 16
+-- characters, those requests can never be confirmed. The column is binary
+-- already, the step of the migration drops exactly the textual rows.
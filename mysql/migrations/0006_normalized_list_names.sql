@@ -0,0 +1,3 @@
+-- list names are normalized by the code, compare them exactly like SQLite
+ALTER TABLE mailing_lists
+  MODIFY email VARCHAR(254) CHARACTER SET utf8mb4 COLLATE utf8mb4_bin NOT NULL
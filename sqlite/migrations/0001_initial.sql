@@ -0,0 +1,25 @@
+CREATE TABLE mailing_lists (
+  id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+  title VARCHAR(100) NOT NULL,
+  email VARCHAR(50) NOT NULL UNIQUE,
+  enabled BOOLEAN NOT NULL DEFAULT 1,
+  language VARCHAR(2) NOT NULL DEFAULT 'EN'
+);
+
+CREATE TABLE users (
+  list_id INTEGER NOT NULL REFERENCES mailing_lists (id) ON DELETE CASCADE,
+  email VARCHAR(50) NOT NULL,
+  password VARCHAR(50) NOT NULL,
+  enabled BOOLEAN NOT NULL DEFAULT 0,
+  PRIMARY KEY(list_id, email)
+);
+
+CREATE TABLE subscriptions (
+  uuid VARCHAR(36) NOT NULL PRIMARY KEY,
+  list_id INTEGER NOT NULL REFERENCES mailing_lists (id) ON DELETE CASCADE,
+  email VARCHAR(50) NOT NULL,
+  timestamp TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  request TEXT NOT NULL
+);
+
+CREATE INDEX subscriptions_list_id ON subscriptions (list_id);
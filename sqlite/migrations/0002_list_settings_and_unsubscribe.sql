@@ -0,0 +1,7 @@
+ALTER TABLE mailing_lists ADD COLUMN archive_url VARCHAR(255) NULL;
+ALTER TABLE mailing_lists ADD COLUMN help_url VARCHAR(255) NULL;
+ALTER TABLE mailing_lists ADD COLUMN unsubscribe_url VARCHAR(255) NULL;
+
+ALTER TABLE subscriptions
+  ADD COLUMN action TEXT NOT NULL DEFAULT 'subscribe'
+  CHECK (action IN ('subscribe', 'unsubscribe'));
@@ -0,0 +1,16 @@
+ALTER TABLE mailing_lists ADD COLUMN fold_case BOOLEAN NOT NULL DEFAULT 1;
+ALTER TABLE mailing_lists ADD COLUMN strip_subaddress BOOLEAN NOT NULL DEFAULT 0;
+
+-- SQLite does not enforce the width of VARCHAR, the address columns stay
+ALTER TABLE users ADD COLUMN original_email VARCHAR(254) NOT NULL DEFAULT '';
+ALTER TABLE subscriptions ADD COLUMN original_email VARCHAR(254) NOT NULL DEFAULT '';
+
+-- existing lists fold case, of members differing in case only one is kept
+DELETE FROM users
+  WHERE EXISTS (SELECT 1 FROM users v
+                WHERE v.list_id = users.list_id AND lower(v.email) = lower(users.email)
+                  AND v.email < users.email);
+
+UPDATE users SET original_email = email, email = lower(email);
+
+UPDATE subscriptions SET original_email = email, email = lower(email);
@@ -0,0 +1,13 @@
+-- SQLite cannot add a column with a non-constant default
+ALTER TABLE subscriptions ADD COLUMN confirmation_sent TIMESTAMP NULL;
+
+UPDATE subscriptions SET confirmation_sent = timestamp;
+
+-- of several pending requests for an address only the newest is kept
+DELETE FROM subscriptions
+  WHERE EXISTS (SELECT 1 FROM subscriptions t
+                WHERE t.list_id = subscriptions.list_id AND t.email = subscriptions.email
+                  AND (t.timestamp > subscriptions.timestamp
+                       OR (t.timestamp = subscriptions.timestamp AND t.uuid > subscriptions.uuid)));
+
+CREATE UNIQUE INDEX subscriptions_list_id_email ON subscriptions (list_id, email);
@@ -0,0 +1,26 @@
+-- tokens become 16 byte blobs, SQLite cannot change the type of a column
+CREATE TABLE subscriptions_binary (
+  uuid BLOB NOT NULL PRIMARY KEY CHECK (length(uuid) = 16),
+  list_id INTEGER NOT NULL REFERENCES mailing_lists (id) ON DELETE CASCADE,
+  email VARCHAR(254) NOT NULL,
+  original_email VARCHAR(254) NOT NULL DEFAULT '',
+  timestamp TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  confirmation_sent TIMESTAMP NULL,
+  request TEXT NOT NULL,
+  action TEXT NOT NULL DEFAULT 'subscribe' CHECK (action IN ('subscribe', 'unsubscribe'))
+);
+
+-- uuid_blob() is registered by the storage, it parses the textual form
+INSERT INTO subscriptions_binary
+    (uuid, list_id, email, original_email, timestamp, confirmation_sent, request, action)
+  SELECT uuid_blob(uuid), list_id, email, original_email, timestamp, confirmation_sent,
+         request, action
+  FROM subscriptions;
+
+DROP TABLE subscriptions;
+
+ALTER TABLE subscriptions_binary RENAME TO subscriptions;
+
+CREATE INDEX subscriptions_list_id ON subscriptions (list_id);
+
+CREATE UNIQUE INDEX subscriptions_list_id_email ON subscriptions (list_id, email);
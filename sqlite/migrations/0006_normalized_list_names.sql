@@ -0,0 +1,2 @@
+-- SQLite compares list names exactly already, the step of the migration
+-- normalizes list names and stored addresses.
@@ -0,0 +1,157 @@
+use crate::{address, error, types};
+use snafu::ResultExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Credentials {
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// What a peer may do, derived from its credentials and the access config.
+#[derive(Default, Debug)]
+pub struct Roles {
+    pub admin: bool,
+    pub mta: bool,
+    pub owned_lists: std::vec::Vec<String>,
+}
+
+/// Reads the credentials of the process on the other end via SO_PEERCRED.
+pub fn peer_credentials(stream: &UnixStream) -> error::Result<Credentials> {
+    let mut ucred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut length = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut ucred as *mut libc::ucred as *mut libc::c_void,
+            &mut length,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error()).context(error::PeerCredentialsError {});
+    }
+    Ok(Credentials {
+        pid: ucred.pid,
+        uid: ucred.uid,
+        gid: ucred.gid,
+    })
+}
+
+pub fn roles(config: &types::Config, credentials: &Credentials) -> Roles {
+    let access = &config.access;
+    let matches = |uids: &[u32], gids: &[u32]| {
+        uids.contains(&credentials.uid) || gids.contains(&credentials.gid)
+    };
+    Roles {
+        admin: credentials.uid == 0
+            || credentials.uid == config.uid
+            || matches(&access.admin_uids, &access.admin_gids),
+        mta: matches(&access.mta_uids, &access.mta_gids),
+        owned_lists: access
+            .list_owners
+            .iter()
+            .filter(|owner| matches(&owner.uids, &owner.gids))
+            .map(|owner| address::list_name(&owner.list))
+            .collect(),
+    }
+}
+
+pub fn authorize(
+    config: &types::Config,
+    credentials: &Credentials,
+    command: &types::Command,
+) -> error::Result<()> {
+    if roles(config, credentials).allows(command.action, command.list_name.as_deref()) {
+        Ok(())
+    } else {
+        Err(error::Error::PermissionDenied {
+            uid: credentials.uid,
+            action: format!("{:?}", command.action),
+        })
+    }
+}
+
+impl Roles {
+    /// Whether the peer may do anything at all.
+    pub fn any(&self) -> bool {
+        self.admin || self.mta || !self.owned_lists.is_empty()
+    }
+
+    pub fn allows(&self, action: types::Action, list_name: Option<&str>) -> bool {
+        use types::Action::*;
+        if self.admin {
+            return true;
+        }
+        let owns_list = list_name
+            .is_some_and(|list_name| self.owned_lists.contains(&address::list_name(list_name)));
+        match action {
+            Alive => self.mta || !self.owned_lists.is_empty(),
+            Subscribe | Unsubscribe | Confirm | Post => self.mta,
+            RenderTemplate | SetList | EnableList | DisableList | Members | AddMember
+            | RemoveMember | SetMember => owns_list,
+            Stop | Reload | CreateList | DeleteList => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Credentials;
+    use crate::testing;
+    use crate::types::Action;
+
+    fn credentials(uid: u32, gid: u32) -> Credentials {
+        Credentials { pid: 1, uid, gid }
+    }
+
+    #[test]
+    fn maps_credentials_to_roles() {
+        let config = testing::config(
+            r#"
+            [access]
+            mta_gids = [8]
+
+            [[access.list_owners]]
+            list = "Gemuese@example.com"
+            uids = [1001]
+            "#,
+        );
+        let list = Some("gemuese@example.com");
+
+        let root = super::roles(&config, &credentials(0, 0));
+        assert!(root.allows(Action::Stop, None));
+        assert!(super::roles(&config, &credentials(1000, 100)).admin);
+
+        let mta = super::roles(&config, &credentials(25, 8));
+        assert!(mta.allows(Action::Post, list));
+        assert!(!mta.allows(Action::AddMember, list));
+
+        let owner = super::roles(&config, &credentials(1001, 100));
+        assert!(owner.allows(Action::AddMember, list));
+        assert!(owner.allows(Action::AddMember, Some("GEMUESE@Example.com")));
+        assert!(!owner.allows(Action::AddMember, Some("other@example.com")));
+        assert!(!owner.allows(Action::DeleteList, list));
+        assert!(!owner.allows(Action::Post, list));
+
+        let nobody = super::roles(&config, &credentials(1002, 100));
+        assert!(!nobody.allows(Action::Alive, None));
+        assert!(!nobody.any());
+        assert!(owner.any() && mta.any());
+    }
+
+    #[test]
+    fn reads_own_credentials_from_socket_pair() {
+        let (client, _server) = std::os::unix::net::UnixStream::pair().unwrap();
+        let credentials = super::peer_credentials(&client).unwrap();
+        assert_eq!(credentials.uid, users::get_current_uid());
+        assert_eq!(credentials.pid, std::process::id() as i32);
+    }
+}
@@ -0,0 +1,262 @@
+use crate::error;
+use serde::{Deserialize, Serialize};
+
+/// Longest address in octets that RFC 5321 allows, the address columns are
+/// as wide.
+pub const MAX_LENGTH: usize = 254;
+
+pub const MAX_LOCAL_PART_LENGTH: usize = 64;
+
+const MAX_LABEL_LENGTH: usize = 63;
+
+/// Starts the sub-address in `jane+lists@example.com`.
+const SUBADDRESS_SEPARATOR: char = '+';
+
+/// How a list compares the local parts of addresses, domains are always
+/// compared without case.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct LocalPartRules {
+    pub fold_case: bool,
+    pub strip_subaddress: bool,
+}
+
+/// An address as its owner wrote it, used for mail and display, and the
+/// normalized form that identifies the owner on a list.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Address {
+    pub original: String,
+    pub normalized: String,
+}
+
+impl Address {
+    /// Checks `address` and normalizes it: the domain is lowercased and
+    /// IDNA-encoded, the local part follows `rules`.
+    pub fn new(address: &str, rules: LocalPartRules) -> error::Result<Address> {
+        let invalid = || error::Error::InvalidMemberAddress {
+            address: address.to_string(),
+        };
+        let at = address.rfind('@').ok_or_else(invalid)?;
+        let (local_part, domain) = (&address[..at], &address[at + 1..]);
+        if local_part.is_empty() || !local_part.chars().all(is_local_part_char) {
+            return Err(invalid());
+        }
+        let domain = idna::domain_to_ascii(domain).map_err(|_| invalid())?;
+        if !is_valid_domain(&domain) {
+            return Err(invalid());
+        }
+        check_length(address, "local part", local_part, MAX_LOCAL_PART_LENGTH)?;
+        let mut local_part = local_part;
+        if rules.strip_subaddress {
+            if let Some(pos) = local_part.find(SUBADDRESS_SEPARATOR).filter(|pos| *pos > 0) {
+                local_part = &local_part[..pos];
+            }
+        }
+        let normalized = if rules.fold_case {
+            format!("{}@{}", local_part.to_lowercase(), domain)
+        } else {
+            format!("{}@{}", local_part, domain)
+        };
+        check_length(address, "address", address, MAX_LENGTH)?;
+        check_length(address, "normalized address", &normalized, MAX_LENGTH)?;
+        Ok(Address {
+            original: address.to_string(),
+            normalized,
+        })
+    }
+
+    /// The address to send mail to, see `delivery_address`.
+    pub fn delivery(&self) -> String {
+        delivery_address(&self.original)
+    }
+}
+
+/// The local part as its owner wrote it and the IDNA-encoded domain, which
+/// MTAs without SMTPUTF8 accept. Addresses are checked when stored, one that
+/// cannot be encoded is returned unchanged.
+pub fn delivery_address(address: &str) -> String {
+    address
+        .rfind('@')
+        .and_then(|at| {
+            idna::domain_to_ascii(&address[at + 1..])
+                .ok()
+                .map(|domain| format!("{}@{}", &address[..at], domain))
+        })
+        .unwrap_or_else(|| address.to_string())
+}
+
+/// Checks the address of a list, its normalized form is the name of the
+/// list. It names the directory of the list's templates, so it must not
+/// contain a path separator.
+pub fn list_address(address: &str) -> error::Result<Address> {
+    let invalid = || error::Error::InvalidListAddress {
+        address: address.to_string(),
+    };
+    if address.contains('/') {
+        return Err(invalid());
+    }
+    let rules = LocalPartRules {
+        fold_case: true,
+        strip_subaddress: false,
+    };
+    Address::new(address, rules).map_err(|err| match err {
+        error::Error::InvalidMemberAddress { .. } => invalid(),
+        err => err,
+    })
+}
+
+/// The name a list is stored and looked up by, see `list_address`. Names
+/// that are no valid address are only lowercased, no list has them.
+pub fn list_name(address: &str) -> String {
+    list_address(address)
+        .map(|address| address.normalized)
+        .unwrap_or_else(|_| address.to_lowercase())
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.original)
+    }
+}
+
+fn is_local_part_char(c: char) -> bool {
+    !(c.is_whitespace() || c.is_control() || "@\"<>(),;:[]\\".contains(c))
+}
+
+/// Letters, digits and inner hyphens in every label, like a host name.
+fn is_valid_domain(domain: &str) -> bool {
+    !domain.is_empty()
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= MAX_LABEL_LENGTH
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+fn check_length(address: &str, part: &'static str, value: &str, limit: usize) -> error::Result<()> {
+    if value.len() > limit {
+        return Err(error::Error::AddressTooLong {
+            address: address.to_string(),
+            part,
+            limit,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Address, LocalPartRules};
+    use crate::error;
+
+    #[test]
+    fn normalizes_by_list_rules() {
+        let exact = LocalPartRules::default();
+        let folding = LocalPartRules {
+            fold_case: true,
+            strip_subaddress: true,
+        };
+        let normalized = |address, rules| Address::new(address, rules).unwrap().normalized;
+        assert_eq!(
+            normalized("Jane.Doe+lists@Example.COM", exact),
+            "Jane.Doe+lists@example.com"
+        );
+        assert_eq!(
+            normalized("Jane.Doe+lists@Example.COM", folding),
+            "jane.doe@example.com"
+        );
+        assert_eq!(
+            normalized("+lists@example.com", folding),
+            "+lists@example.com"
+        );
+        assert_eq!(
+            normalized("Jürgen@Bücher.example", folding),
+            "jürgen@xn--bcher-kva.example"
+        );
+        let address = Address::new("Jürgen@Bücher.example", folding).unwrap();
+        assert_eq!(address.original, "Jürgen@Bücher.example");
+        assert_eq!(address.to_string(), "Jürgen@Bücher.example");
+        assert_eq!(address.delivery(), "Jürgen@xn--bcher-kva.example");
+
+        for invalid in &[
+            "jane",
+            "@example.com",
+            "jane@",
+            "jane doe@example.com",
+            "jane@exa mple.com",
+            "jane@-example.com",
+            "jane@example..com",
+            "\"jane\"@example.com",
+        ] {
+            assert!(
+                matches!(
+                    Address::new(invalid, folding),
+                    Err(error::Error::InvalidMemberAddress { .. })
+                ),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn checks_list_addresses() {
+        assert!(super::list_address("gemuese@example.com").is_ok());
+        assert_eq!(
+            super::list_name("Gemüse@Bücher.Example"),
+            "gemüse@xn--bcher-kva.example"
+        );
+        assert_eq!(super::list_name("Gemuese"), "gemuese");
+        assert!(super::list_address("gemuese+list@lists.example-domain.com").is_ok());
+        for invalid in &[
+            "gemuese",
+            "gemuese@",
+            "gem use@example.com",
+            "gemuese@example..com",
+            "<gemuese@example.com>",
+            "../../etc@example.com",
+            "lists/gemuese@example.com",
+        ] {
+            assert!(
+                matches!(
+                    super::list_address(invalid),
+                    Err(error::Error::InvalidListAddress { .. })
+                ),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn enforces_length_limits() {
+        let rules = LocalPartRules::default();
+        let local_part = "a".repeat(super::MAX_LOCAL_PART_LENGTH);
+        assert!(Address::new(&format!("{}@example.com", local_part), rules).is_ok());
+        assert!(matches!(
+            Address::new(&format!("{}a@example.com", local_part), rules),
+            Err(error::Error::AddressTooLong {
+                part: "local part",
+                ..
+            })
+        ));
+        let domain = vec!["b".repeat(60); 4].join(".");
+        assert!(matches!(
+            Address::new(&format!("{}@{}", local_part, domain), rules),
+            Err(error::Error::AddressTooLong {
+                part: "address",
+                ..
+            })
+        ));
+        // short in Unicode, too long once encoded
+        let domain = vec!["ü"; 60].join(".");
+        assert!(matches!(
+            Address::new(&format!("jane@{}", domain), rules),
+            Err(error::Error::AddressTooLong {
+                part: "normalized address",
+                ..
+            })
+        ));
+    }
+}
@@ -4,6 +4,9 @@ use std::io::Read;
 
 static PROGRAM: &str = "simplemmclnt";
 static CLIENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+/// sysexits.h
+const EX_TEMPFAIL: i32 = 75;
+const EX_CONFIG: i32 = 78;
 
 fn main() {
     if let Err(e) = run() {
@@ -12,26 +15,87 @@ fn main() {
 }
 
 fn run() -> error::Result<()> {
-    let (config, matches) = read_config()?;
+    let matches = parse_args();
+    // a configuration with problems is what check-config is for
+    if matches.subcommand_name() == Some("check-config") {
+        return action_check_config(&matches);
+    }
+    let config = read_config(&matches)?;
     match matches.subcommand_name().unwrap() {
         "stop" => action_stop(&config),
-        "ping" => action_ping(&config),
+        "ping" => action_ping(&config, &matches),
+        "reload" => action_reload(&config),
         "version" => action_client_info(),
+        "migrate" => action_migrate(&config),
         "subscribe" => action_subscribe(&config, &matches),
+        "unsubscribe" => action_unsubscribe(&config, &matches),
+        "confirm" => action_confirm(&config, &matches),
+        "post" => action_post(&config, &matches),
+        "render-template" => action_render_template(&config, &matches),
+        "create-list" => action_list_admin(&config, &matches, types::Action::CreateList),
+        "delete-list" => action_list_admin(&config, &matches, types::Action::DeleteList),
+        "enable-list" => action_list_admin(&config, &matches, types::Action::EnableList),
+        "disable-list" => action_list_admin(&config, &matches, types::Action::DisableList),
+        "set-list" => action_list_admin(&config, &matches, types::Action::SetList),
+        "members" => action_members(&config, &matches),
+        "add-member" => action_member_admin(&config, &matches, types::Action::AddMember),
+        "remove-member" => action_member_admin(&config, &matches, types::Action::RemoveMember),
+        "set-member" => action_member_admin(&config, &matches, types::Action::SetMember),
         _ => Ok(()),
     }
 }
 
 fn action_stop(config: &types::Config) -> error::Result<()> {
-    let _ = client::check_server_is_running(&config)?;
-    client::stop_daemon(config)
+    let _ = client::check_server_is_running(config)?;
+    let (message, _) = client::stop_daemon(config)?;
+    println!("{}", message);
+    Ok(())
 }
 
-fn action_ping(config: &types::Config) -> error::Result<()> {
-    let (pid, state) = client::check_server_is_running(&config)?;
+fn action_reload(config: &types::Config) -> error::Result<()> {
+    let response = client::request(config, types::Action::Reload, None, None)?;
+    println!("{}", response.message);
+    Ok(())
+}
+
+fn action_ping(config: &types::Config, matches: &clap::ArgMatches) -> error::Result<()> {
+    let status = client::check_server_is_running(config)?;
+    let verbose = matches
+        .subcommand_matches("ping")
+        .is_some_and(|matches| matches.is_present("verbose"));
+    if verbose {
+        let output =
+            serde_json::to_string_pretty(&status).context(error::RequestSerializeError {})?;
+        println!("{}", output);
+        return Ok(());
+    }
     println!(
-        "simplemmd v{}, pid = {}, server_start_time: {}, uid = {}, gid = {}",
-        state.server_version, pid, state.start_time, state.config.uid, state.config.gid
+        "simplemmd v{}, pid = {}, server_start_time: {}, uptime: {}s",
+        status.server_version, status.pid, status.start_time, status.uptime_seconds
+    );
+    println!(
+        "requests: {} ({} failed, {} refused, {} rejected busy), workers: {}/{} busy, {}/{} queued",
+        status.requests.total,
+        status.requests.failed,
+        status.requests.refused,
+        status.requests.rejected_busy,
+        status.workers.busy,
+        status.workers.threads,
+        status.workers.queued,
+        status.workers.queue_size
+    );
+    println!(
+        "database: {} {}, outbound deliveries: {}, pending confirmations: {}",
+        status.database.backend,
+        if status.database.healthy {
+            "healthy"
+        } else {
+            "unhealthy"
+        },
+        status.outbound_deliveries,
+        status
+            .pending_confirmations
+            .map_or("unknown".to_string(), |count| count.to_string())
     );
     Ok(())
 }
@@ -41,9 +105,136 @@ fn action_client_info() -> error::Result<()> {
     Ok(())
 }
 
+fn action_check_config(matches: &clap::ArgMatches) -> error::Result<()> {
+    let config_file_name = matches.value_of("config").unwrap_or("/etc/simplemm.conf");
+    let problems = simplemm::config::check_file(config_file_name)?;
+    if problems.is_empty() {
+        println!("Configuration {} is valid", config_file_name);
+        return Ok(());
+    }
+    println!("Configuration {} has problems:", config_file_name);
+    for problem in problems {
+        println!("  {}", problem);
+    }
+    std::process::exit(EX_CONFIG)
+}
+
+fn action_migrate(config: &types::Config) -> error::Result<()> {
+    let applied = simplemm::database::migrate(config)?;
+    if applied.is_empty() {
+        println!("Database schema is up to date");
+    }
+    for version in applied {
+        println!("Migrated database schema to version {}", version);
+    }
+    Ok(())
+}
+
 fn action_subscribe(config: &types::Config, matches: &clap::ArgMatches) -> error::Result<()> {
+    send_mail_from_stdin(config, matches, "subscribe", types::Action::Subscribe)
+}
+
+fn action_unsubscribe(config: &types::Config, matches: &clap::ArgMatches) -> error::Result<()> {
+    send_mail_from_stdin(config, matches, "unsubscribe", types::Action::Unsubscribe)
+}
+
+fn action_post(config: &types::Config, matches: &clap::ArgMatches) -> error::Result<()> {
+    send_mail_from_stdin(config, matches, "post", types::Action::Post)
+}
+
+fn action_render_template(config: &types::Config, matches: &clap::ArgMatches) -> error::Result<()> {
+    let matches = matches.subcommand_matches("render-template").unwrap();
+    let mailing_list = matches.value_of("list_name").unwrap();
+    let template = matches.value_of("template").unwrap();
+    let rendered: String = client::send_and_read(
+        config,
+        types::Action::RenderTemplate,
+        Some(mailing_list.to_string()),
+        Some(template.to_string()),
+    )?;
+    println!("{}", rendered);
+    Ok(())
+}
+
+fn action_list_admin(
+    config: &types::Config,
+    matches: &clap::ArgMatches,
+    action: types::Action,
+) -> error::Result<()> {
+    let (_, matches) = matches.subcommand();
+    let matches = matches.unwrap();
+    let mailing_list = matches.value_of("list_name").unwrap();
+    let settings = types::ListSettings {
+        title: matches.value_of("title").map(str::to_string),
+        enabled: None,
+        language: matches.value_of("language").map(str::to_string),
+        archive_url: matches.value_of("archive_url").map(str::to_string),
+        help_url: matches.value_of("help_url").map(str::to_string),
+        unsubscribe_url: matches.value_of("unsubscribe_url").map(str::to_string),
+        fold_case: matches.value_of("fold_case").map(|value| value == "true"),
+        strip_subaddress: matches
+            .value_of("strip_subaddress")
+            .map(|value| value == "true"),
+    };
+    let data = serde_json::to_string(&settings).context(error::RequestSerializeError {})?;
+    let response = client::request(config, action, Some(mailing_list.to_string()), Some(data))?;
+    println!("{}", response.message);
+    Ok(())
+}
+
+fn action_members(config: &types::Config, matches: &clap::ArgMatches) -> error::Result<()> {
+    let matches = matches.subcommand_matches("members").unwrap();
+    let mailing_list = matches.value_of("list_name").unwrap();
+    let query = types::MemberQuery {
+        offset: parse_number(matches, "offset")?,
+        limit: parse_number(matches, "limit")?,
+    };
+    let data = serde_json::to_string(&query).context(error::RequestSerializeError {})?;
+    let page: types::MemberPage = client::send_and_read(
+        config,
+        types::Action::Members,
+        Some(mailing_list.to_string()),
+        Some(data),
+    )?;
+    let output = serde_json::to_string_pretty(&page).context(error::RequestSerializeError {})?;
+    println!("{}", output);
+    Ok(())
+}
+
+fn action_member_admin(
+    config: &types::Config,
+    matches: &clap::ArgMatches,
+    action: types::Action,
+) -> error::Result<()> {
+    let (_, matches) = matches.subcommand();
+    let matches = matches.unwrap();
+    let mailing_list = matches.value_of("list_name").unwrap();
+    let change = types::MemberChange {
+        email: matches.value_of("email").unwrap().to_string(),
+        enabled: matches.value_of("enabled").map(|enabled| enabled == "true"),
+        welcome: matches.is_present("welcome"),
+    };
+    let data = serde_json::to_string(&change).context(error::RequestSerializeError {})?;
+    let response = client::request(config, action, Some(mailing_list.to_string()), Some(data))?;
+    println!("{}", response.message);
+    Ok(())
+}
+
+fn parse_number(matches: &clap::ArgMatches, name: &'static str) -> error::Result<u64> {
+    let value = matches.value_of(name).unwrap();
+    value
+        .parse()
+        .context(error::InvalidArgument { name, value })
+}
+
+fn send_mail_from_stdin(
+    config: &types::Config,
+    matches: &clap::ArgMatches,
+    subcommand: &str,
+    action: types::Action,
+) -> error::Result<()> {
     let mailing_list = matches
-        .subcommand_matches("subscribe")
+        .subcommand_matches(subcommand)
         .unwrap()
         .value_of("list_name")
         .unwrap();
@@ -51,15 +242,98 @@ fn action_subscribe(config: &types::Config, matches: &clap::ArgMatches) -> error
     std::io::stdin()
         .read_to_string(&mut email_content)
         .context(error::ReadStdinError {})?;
-    client::send_and_read(
+    let response = client::request(
         config,
-        types::Action::Subscribe,
+        action,
         Some(mailing_list.to_string()),
         Some(email_content),
     )?;
+    println!("{}", response.message);
     Ok(())
 }
 
+fn action_confirm(config: &types::Config, matches: &clap::ArgMatches) -> error::Result<()> {
+    let token = matches
+        .subcommand_matches("confirm")
+        .unwrap()
+        .value_of("token");
+    let data = match token {
+        Some(token) => token.to_string(),
+        None => {
+            let mut email_content = String::new();
+            std::io::stdin()
+                .read_to_string(&mut email_content)
+                .context(error::ReadStdinError {})?;
+            email_content
+        }
+    };
+    let response = client::request(config, types::Action::Confirm, None, Some(data))?;
+    println!("{}", response.message);
+    Ok(())
+}
+
+fn list_name_arg<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("list_name")
+        .help("Name of the mailing list")
+        .required(true)
+}
+
+fn list_settings_args<'a, 'b>() -> std::vec::Vec<clap::Arg<'a, 'b>> {
+    let setting = |name, long, value_name, help| {
+        clap::Arg::with_name(name)
+            .long(long)
+            .value_name(value_name)
+            .help(help)
+            .takes_value(true)
+    };
+    vec![
+        setting("title", "title", "VALUE", "Title of the mailing list"),
+        setting("language", "language", "VALUE", "Two letter language code"),
+        setting(
+            "archive_url",
+            "archive-url",
+            "VALUE",
+            "Archive URL, empty to remove",
+        ),
+        setting("help_url", "help-url", "VALUE", "Help URL, empty to remove"),
+        setting(
+            "unsubscribe_url",
+            "unsubscribe-url",
+            "VALUE",
+            "HTTPS one-click unsubscribe URL, empty to remove",
+        ),
+        setting(
+            "fold_case",
+            "fold-case",
+            "BOOL",
+            "Whether new member addresses are compared without case",
+        )
+        .possible_values(&["true", "false"]),
+        setting(
+            "strip_subaddress",
+            "strip-subaddress",
+            "BOOL",
+            "Whether the +sub-address of new member addresses is ignored",
+        )
+        .possible_values(&["true", "false"]),
+    ]
+}
+
+fn member_email_arg<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("email")
+        .help("Address of the member")
+        .required(true)
+}
+
+fn member_enabled_arg<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("enabled")
+        .long("enabled")
+        .value_name("BOOL")
+        .possible_values(&["true", "false"])
+        .help("Whether the member receives posts")
+        .takes_value(true)
+}
+
 fn parse_args<'a>() -> clap::ArgMatches<'a> {
     let app = clap::App::new(PROGRAM)
         .version(CLIENT_VERSION)
@@ -74,27 +348,144 @@ fn parse_args<'a>() -> clap::ArgMatches<'a> {
                 .takes_value(true),
         )
         .subcommand(clap::SubCommand::with_name("stop").about("Stop simplemmd daemon"))
-        .subcommand(clap::SubCommand::with_name("ping").about("Get server status"))
+        .subcommand(
+            clap::SubCommand::with_name("ping")
+                .about("Get server status")
+                .arg(
+                    clap::Arg::with_name("verbose")
+                        .short("v")
+                        .long("verbose")
+                        .help("Print the full status, with the configuration for admins"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("reload")
+                .about("Reload the configuration of simplemmd daemon"),
+        )
         .subcommand(clap::SubCommand::with_name("version").about("Get client version"))
+        .subcommand(
+            clap::SubCommand::with_name("migrate")
+                .about("Migrate the database schema, works without a running daemon"),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("check-config")
+                .about("Report every problem of the configuration file"),
+        )
         .subcommand(
             clap::SubCommand::with_name("subscribe")
                 .about("Subscribe to mailing list")
+                .arg(list_name_arg()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("unsubscribe")
+                .about("Unsubscribe from mailing list")
+                .arg(list_name_arg()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("post")
+                .about("Distribute a post read from stdin to the mailing list")
+                .arg(list_name_arg()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("render-template")
+                .about("Render a mail template of a mailing list with sample data")
+                .arg(list_name_arg())
                 .arg(
-                    clap::Arg::with_name("list_name")
-                        .help("Name of the mailing list")
+                    clap::Arg::with_name("template")
+                        .help("Template name")
+                        .possible_values(&[
+                            "confirmation",
+                            "welcome",
+                            "already-subscribed",
+                            "goodbye",
+                            "help",
+                            "rejection",
+                        ])
                         .required(true),
                 ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("create-list")
+                .about("Create a mailing list")
+                .arg(list_name_arg())
+                .args(&list_settings_args()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("set-list")
+                .about("Change the settings of a mailing list")
+                .arg(list_name_arg())
+                .args(&list_settings_args()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("delete-list")
+                .about("Delete a mailing list with all members and subscriptions")
+                .arg(list_name_arg()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("enable-list")
+                .about("Enable a mailing list")
+                .arg(list_name_arg()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("disable-list")
+                .about("Disable a mailing list")
+                .arg(list_name_arg()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("members")
+                .about("List the members of a mailing list as JSON")
+                .arg(list_name_arg())
+                .arg(
+                    clap::Arg::with_name("offset")
+                        .long("offset")
+                        .value_name("N")
+                        .help("Number of members to skip")
+                        .default_value("0"),
+                )
+                .arg(
+                    clap::Arg::with_name("limit")
+                        .long("limit")
+                        .value_name("N")
+                        .help("Maximum number of members to list")
+                        .default_value("100"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("add-member")
+                .about("Add a member to a mailing list without confirmation")
+                .arg(list_name_arg())
+                .arg(member_email_arg())
+                .arg(member_enabled_arg())
+                .arg(
+                    clap::Arg::with_name("welcome")
+                        .long("welcome")
+                        .help("Send the welcome mail to the new member"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("remove-member")
+                .about("Remove a member from a mailing list")
+                .arg(list_name_arg())
+                .arg(member_email_arg()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("set-member")
+                .about("Enable or disable a member of a mailing list")
+                .arg(list_name_arg())
+                .arg(member_email_arg())
+                .arg(member_enabled_arg().required(true)),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("confirm")
+                .about("Confirm a subscription, reads the reply mail from stdin without token")
+                .arg(clap::Arg::with_name("token").help("Confirmation token")),
         );
     app.get_matches()
 }
 
-fn read_config<'a>() -> error::Result<(types::Config, clap::ArgMatches<'a>)> {
-    let arg_matches = parse_args();
-    let config_file_name = arg_matches
-        .value_of("config")
-        .unwrap_or("/etc/simplemm.conf");
-    let config = simplemm::config::read_config(config_file_name)?;
-    Ok((config, arg_matches))
+fn read_config(matches: &clap::ArgMatches) -> error::Result<types::Config> {
+    let config_file_name = matches.value_of("config").unwrap_or("/etc/simplemm.conf");
+    simplemm::config::read_config(config_file_name)
 }
 
 fn error_abort(error: error::Error) -> ! {
@@ -102,5 +493,9 @@ fn error_abort(error: error::Error) -> ! {
     if let Some(backtrace) = ErrorCompat::backtrace(&error) {
         eprintln!("{}", backtrace);
     }
-    std::process::exit(-1)
+    // a busy daemon is a temporary failure, the MTA will retry the delivery
+    match error.kind() {
+        error::ErrorKind::Busy => std::process::exit(EX_TEMPFAIL),
+        _ => std::process::exit(-1),
+    }
 }
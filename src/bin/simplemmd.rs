@@ -1,3 +1,4 @@
+use simplemm::storage::Storage;
 use simplemm::{error, state, types};
 
 use snafu::{ErrorCompat, ResultExt};
@@ -13,22 +14,49 @@ fn main() {
 }
 
 fn run() -> error::Result<()> {
-    initialize_syslog()?;
-    let config = read_config()?;
-    pre_daemonize_checks(&config)?;
-    let socket = bind_to_socket(&config)?;
-    daemonize(&config)?;
-    handle_requests(socket);
-    Ok(())
+    let arg_matches = parse_args();
+    let (config_file, config) = read_config(&arg_matches)?;
+    simplemm::config::validate(&config)?;
+    simplemm::logging::initialize(&config.logging)?;
+    if arg_matches.is_present("migrate") {
+        return migrate(&config);
+    }
+    let storage = pre_daemonize_checks(&config)?;
+    let socket = match simplemm::systemd::listen_socket()? {
+        Some(socket) => socket,
+        None => bind_to_socket(&config)?,
+    };
+    if arg_matches.is_present("foreground") {
+        run_in_foreground(&config_file, &config, storage)?;
+    } else {
+        daemonize(&config_file, &config, storage)?;
+    }
+    handle_requests(socket, &config)
 }
 
-fn read_config() -> error::Result<types::Config> {
-    let arg_matches = parse_args();
+/// The configuration and the absolute path of its file, for reloading it
+/// from the working directory.
+fn read_config(arg_matches: &clap::ArgMatches) -> error::Result<(String, types::Config)> {
     let config_file_name = arg_matches
         .value_of("config")
         .unwrap_or("/etc/simplemm.conf");
-    let config = simplemm::config::read_config(config_file_name)?;
-    Ok(config)
+    let mut config = simplemm::config::read_config(config_file_name)?;
+    let config_file = std::fs::canonicalize(config_file_name)
+        .context(error::FileOpenError {
+            filename: config_file_name,
+        })?
+        .to_string_lossy()
+        .to_string();
+    match arg_matches.value_of("log-to") {
+        Some("stderr") => config.logging.destination = types::LogDestination::Stderr,
+        Some("file") => config.logging.destination = types::LogDestination::File,
+        Some("syslog") => config.logging.destination = types::LogDestination::Syslog,
+        _ => {}
+    }
+    if let Some(level) = arg_matches.value_of("log-level") {
+        config.logging.level = level.to_string();
+    }
+    Ok((config_file, config))
 }
 
 fn parse_args<'a>() -> clap::ArgMatches<'a> {
@@ -43,46 +71,152 @@ fn parse_args<'a>() -> clap::ArgMatches<'a> {
                 .help("configuration file")
                 .takes_value(true),
         )
+        .arg(
+            clap::Arg::with_name("migrate")
+                .long("migrate")
+                .help("Migrate the database schema and exit"),
+        )
+        .arg(
+            clap::Arg::with_name("foreground")
+                .short("f")
+                .long("foreground")
+                .help("Stay in the foreground, for supervisors and containers"),
+        )
+        .arg(
+            clap::Arg::with_name("log-to")
+                .long("log-to")
+                .value_name("DESTINATION")
+                .help("Log to syslog, stderr or the file of the configuration")
+                .possible_values(&["syslog", "stderr", "file"])
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("log-level")
+                .long("log-level")
+                .value_name("LEVEL")
+                .help("Log level: off, error, warn, info, debug or trace")
+                .takes_value(true),
+        )
         .get_matches();
     matches
 }
 
-fn pre_daemonize_checks(config: &types::Config) -> error::Result<()> {
-    simplemm::database::check_database(&config)?;
-    simplemm::file::check_working_dir(&config)?;
-    simplemm::file::check_pid_file(&config)?;
+fn migrate(config: &types::Config) -> error::Result<()> {
+    let applied = simplemm::database::migrate(config)?;
+    if applied.is_empty() {
+        println!("Database schema is up to date");
+    }
+    for version in applied {
+        log::info!("Migrated database schema to version {}", version);
+        println!("Migrated database schema to version {}", version);
+    }
     Ok(())
 }
 
-fn daemonize(config: &types::Config) -> error::Result<()> {
-    let daemonize = daemonize::Daemonize::new()
-        .pid_file(&config.pid_file)
-        .chown_pid_file(true)
+fn pre_daemonize_checks(config: &types::Config) -> error::Result<Box<dyn Storage>> {
+    let storage = simplemm::database::open_database(config)?;
+    simplemm::file::check_working_dir(config)?;
+    simplemm::file::check_pid_file(config)?;
+    Ok(storage)
+}
+
+fn daemonize(
+    config_file: &str,
+    config: &types::Config,
+    storage: Box<dyn Storage>,
+) -> error::Result<()> {
+    let mut daemonize = daemonize::Daemonize::new();
+    if let Some(pid_file) = &config.pid_file {
+        daemonize = daemonize.pid_file(pid_file).chown_pid_file(true);
+    }
+    let daemonize = daemonize
         .working_directory(&config.working_dir)
         .user(config.uid)
         .group(config.gid)
         .umask(0o777);
 
+    drop_supplementary_groups(config)?;
     daemonize.start().context(error::DaemonizeError {})?;
-    simplemm::state::start_server(&config)?;
+    simplemm::state::start_server(config_file, config, storage)?;
+    Ok(())
+}
+
+/// `--foreground` for supervisors and containers: no fork and no pid file,
+/// the supervisor tracks the process. Changes the working directory and
+/// drops privileges like `daemonize`.
+fn run_in_foreground(
+    config_file: &str,
+    config: &types::Config,
+    storage: Box<dyn Storage>,
+) -> error::Result<()> {
+    std::env::set_current_dir(&config.working_dir).context(error::WorkingDirError {
+        path: &config.working_dir,
+    })?;
+    unsafe {
+        libc::umask(0o777);
+    }
+    drop_privileges(config)?;
+    simplemm::state::start_server(config_file, config, storage)?;
     Ok(())
 }
 
+fn drop_privileges(config: &types::Config) -> error::Result<()> {
+    drop_supplementary_groups(config)?;
+    let result = unsafe {
+        if libc::setgid(config.gid) != 0 || libc::setuid(config.uid) != 0 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    };
+    result.context(error::PrivilegeDropError {
+        uid: config.uid,
+        gid: config.gid,
+    })
+}
+
+/// Root's supplementary groups would survive `setgid` and `setuid`. Only
+/// root may change them, so others keep theirs.
+fn drop_supplementary_groups(config: &types::Config) -> error::Result<()> {
+    let result = unsafe {
+        if libc::geteuid() == 0 && libc::setgroups(0, std::ptr::null()) != 0 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    };
+    result.context(error::PrivilegeDropError {
+        uid: config.uid,
+        gid: config.gid,
+    })
+}
+
 fn bind_to_socket(config: &types::Config) -> error::Result<UnixListener> {
     let path = std::path::Path::new(&config.socket);
-    let _ = std::fs::remove_file(&path);
-    let listener = UnixListener::bind(&path).context(error::SocketBindError {
+    let _ = std::fs::remove_file(path);
+    let listener = UnixListener::bind(path).context(error::SocketBindError {
         path: path.to_string_lossy().to_string(),
     })?;
     set_socket_permissions(&config.socket)?;
     Ok(listener)
 }
 
-fn handle_requests(listener: UnixListener) {
+fn handle_requests(listener: UnixListener, config: &types::Config) -> error::Result<()> {
+    simplemm::shutdown::register_listener(&listener);
+    let workers = simplemm::workers::WorkerPool::start(&config.workers, handle_client)?;
     for stream in listener.incoming() {
+        if simplemm::shutdown::is_stopping() {
+            // the thread draining the requests exits the process
+            loop {
+                std::thread::park();
+            }
+        }
         match stream {
             Ok(stream) => {
-                std::thread::spawn(move || handle_client(stream));
+                if let Err(stream) = workers.dispatch(stream) {
+                    log::warn!("All workers busy, rejecting connection");
+                    simplemm::request::reject_busy(stream);
+                }
             }
             Err(err) => {
                 log::error!("Error: {}", err);
@@ -90,33 +224,16 @@ fn handle_requests(listener: UnixListener) {
             }
         }
     }
+    Ok(())
 }
 
-fn handle_client(stream: UnixStream) {
-    let reader = std::io::BufReader::new(&stream);
-    let command: error::Result<types::Command> =
-        serde_json::from_reader(reader).context(error::RequestParseError {});
-    match command {
-        Ok(command) => simplemm::request::process_request(command, stream),
-        Err(err) => log::warn!("Could not parse request: {:?}", err),
+fn handle_client(stream: UnixStream, queued: simplemm::shutdown::InFlight) {
+    match simplemm::access::peer_credentials(&stream) {
+        Ok(credentials) => simplemm::request::handle_connection(stream, credentials, queued),
+        Err(err) => log::warn!("Rejected connection: {}", err),
     }
 }
 
-fn initialize_syslog() -> error::Result<()> {
-    let formatter = syslog::Formatter3164 {
-        facility: syslog::Facility::LOG_USER,
-        hostname: None,
-        process: "simplemmd".into(),
-        pid: 0,
-    };
-
-    let logger = syslog::unix(formatter).context(error::SyslogError {})?;
-    log::set_boxed_logger(Box::new(syslog::BasicLogger::new(logger)))
-        .map(|()| log::set_max_level(log::LevelFilter::Info))
-        .context(error::SetLoggerError {})?;
-    Ok(())
-}
-
 fn error_abort(error: error::Error) -> ! {
     log::error!("Error: {}", error);
     eprintln!("Error: {}", error);
@@ -127,6 +244,7 @@ fn error_abort(error: error::Error) -> ! {
     std::process::exit(-1)
 }
 
+/// Everybody may connect, requests are authorized by the peer credentials.
 fn set_socket_permissions(socket: &str) -> error::Result<()> {
     std::fs::set_permissions(socket, std::fs::Permissions::from_mode(0o777))
         .context(error::SocketPermissionError { socket })
@@ -1,23 +1,18 @@
-use crate::{error, types};
+use crate::{error, protocol, types};
 use snafu::ResultExt;
-use std::io::Read;
 use std::os::unix::net::UnixStream;
 
-pub fn stop_daemon(config: &types::Config) -> error::Result<()> {
-    send_no_read(config, types::Action::Stop, None, None)?;
-    let state = get_server_state(config);
-    if state.is_err() {
-        println!("Server stopped successfully");
-    } else {
-        println!("Could not stop server");
-    }
-    Ok(())
+/// Stops the daemon, returns its message and whether all requests in flight
+/// finished.
+pub fn stop_daemon(config: &types::Config) -> error::Result<(String, types::ShutdownReport)> {
+    let response = request(config, types::Action::Stop, None, None)?;
+    let payload = response.payload.unwrap_or(serde_json::Value::Null);
+    let report = serde_json::from_value(payload).context(error::RequestParseError {})?;
+    Ok((response.message, report))
 }
 
-pub fn check_server_is_running(config: &types::Config) -> error::Result<(i64, types::DaemonState)> {
-    let pid = get_server_pid(config)?;
-    let state = get_server_state(&config)?;
-    Ok((pid, state))
+pub fn check_server_is_running(config: &types::Config) -> error::Result<types::ServerStatus> {
+    get_server_status(config)
 }
 
 pub fn send_and_read<T: for<'de> serde::de::Deserialize<'de>>(
@@ -26,73 +21,86 @@ pub fn send_and_read<T: for<'de> serde::de::Deserialize<'de>>(
     list_name: Option<String>,
     data: Option<String>,
 ) -> error::Result<T> {
-    let stream = send(config, action, list_name, data)?;
-    let result: T = serde_json::from_reader(&stream).context(error::RequestParseError {})?;
-    shutdown_socket(&stream, std::net::Shutdown::Both, config)?;
+    let response = request(config, action, list_name, data)?;
+    let payload = response.payload.unwrap_or(serde_json::Value::Null);
+    let result: T = serde_json::from_value(payload).context(error::RequestParseError {})?;
     Ok(result)
 }
 
-pub fn send_no_read(
+pub fn request(
     config: &types::Config,
     action: types::Action,
     list_name: Option<String>,
     data: Option<String>,
-) -> error::Result<()> {
-    let stream = send(config, action, list_name, data)?;
-    shutdown_socket(&stream, std::net::Shutdown::Both, config)?;
-    Ok(())
+) -> error::Result<types::Response> {
+    let mut connection = Connection::open(config)?;
+    let response = connection.request(action, list_name, data)?;
+    connection.close()?;
+    Ok(response)
 }
 
-fn shutdown_socket(
-    stream: &UnixStream,
-    shutdown: std::net::Shutdown,
-    config: &types::Config,
-) -> error::Result<()> {
-    stream.shutdown(shutdown).context(error::SocketCloseError {
-        socket: &config.socket,
-    })
+/// A handshaked connection to the daemon that can carry several requests.
+pub struct Connection<'a> {
+    config: &'a types::Config,
+    stream: UnixStream,
+    originator: String,
+    pub server: protocol::Hello,
 }
 
-fn get_server_pid(config: &types::Config) -> error::Result<i64> {
-    let mut file = std::fs::File::open(&config.pid_file).context(error::PidFileReadError {
-        filename: &config.pid_file,
-    })?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)
-        .context(error::PidFileReadError {
-            filename: &config.pid_file,
-        })?;
-    let pid: i64 = contents.trim().parse().context(error::PidFileParseError {
-        filename: &config.pid_file,
-    })?;
-    Ok(pid)
-}
+impl<'a> Connection<'a> {
+    pub fn open(config: &'a types::Config) -> error::Result<Connection<'a>> {
+        let mut stream =
+            UnixStream::connect(&config.socket).context(error::SocketConnectError {
+                socket: &config.socket,
+            })?;
+        let server = protocol::client_handshake(&mut stream)?;
+        let uid = users::get_current_uid();
+        let originator = users::get_user_by_uid(uid).map_or("<None>".to_string(), |user| {
+            user.name().to_string_lossy().to_string()
+        });
+        Ok(Connection {
+            config,
+            stream,
+            originator,
+            server,
+        })
+    }
 
-fn get_server_state(config: &types::Config) -> error::Result<types::DaemonState> {
-    send_and_read(config, types::Action::Alive, None, None)
-}
+    pub fn request(
+        &mut self,
+        action: types::Action,
+        list_name: Option<String>,
+        data: Option<String>,
+    ) -> error::Result<types::Response> {
+        let command = types::Command {
+            action,
+            originator: self.originator.clone(),
+            list_name,
+            data,
+        };
+        protocol::write_command(&mut self.stream, &command)?;
+        let response: types::Response =
+            protocol::read_frame(&mut self.stream)?.ok_or(error::Error::ProtocolIoError {
+                source: std::io::Error::from(std::io::ErrorKind::UnexpectedEof),
+            })?;
+        if response.status == types::Status::Error {
+            return Err(error::Error::ServerError {
+                kind: response.error_kind.unwrap_or(error::ErrorKind::Internal),
+                message: response.message,
+            });
+        }
+        Ok(response)
+    }
 
-fn send(
-    config: &types::Config,
-    action: types::Action,
-    list_name: Option<String>,
-    data: Option<String>,
-) -> error::Result<UnixStream> {
-    let stream = UnixStream::connect(&config.socket).context(error::SocketConnectError {
-        socket: &config.socket,
-    })?;
+    pub fn close(self) -> error::Result<()> {
+        self.stream
+            .shutdown(std::net::Shutdown::Both)
+            .context(error::SocketCloseError {
+                socket: &self.config.socket,
+            })
+    }
+}
 
-    let uid = users::get_current_uid();
-    let user = users::get_user_by_uid(uid).map_or("<None>".to_string(), |user| {
-        user.name().to_string_lossy().to_string()
-    });
-    let command = types::Command {
-        action,
-        originator: user,
-        list_name,
-        data,
-    };
-    serde_json::to_writer(&stream, &command).context(error::RequestSerializeError {})?;
-    shutdown_socket(&stream, std::net::Shutdown::Write, config)?;
-    Ok(stream)
+fn get_server_status(config: &types::Config) -> error::Result<types::ServerStatus> {
+    send_and_read(config, types::Action::Alive, None, None)
 }
@@ -1,17 +1,349 @@
-use crate::{error, types};
+use crate::{error, logging, storage, types};
 
 use std::fs::File;
 use std::io::{BufReader, Read};
+use std::path::Path;
 
 use snafu::ResultExt;
 
+/// Environment variables starting with this prefix override settings of the
+/// file: `SIMPLEMM_DB_URL` sets `db_url`, `SIMPLEMM_DB_POOL__MAX_CONNECTIONS`
+/// sets `max_connections` of `[db_pool]`.
+const ENV_PREFIX: &str = "SIMPLEMM_";
+
 pub fn read_config(filename: &str) -> error::Result<types::Config> {
+    let contents = read_file(filename)?;
+    let mut value: toml::Value =
+        toml::from_str(&contents).context(error::TomlParsingError { filename })?;
+    apply_env_overrides(&mut value, std::env::vars());
+    let config = value
+        .try_into()
+        .context(error::TomlParsingError { filename })?;
+    Ok(config)
+}
+
+/// Every problem of the configuration file, including every setting of the
+/// wrong type. Only a syntax error stops the check early.
+pub fn check_file(filename: &str) -> error::Result<std::vec::Vec<String>> {
+    let contents = read_file(filename)?;
+    let mut value: toml::Value = match toml::from_str(&contents) {
+        Ok(value) => value,
+        Err(err) => return Ok(vec![err.to_string()]),
+    };
+    apply_env_overrides(&mut value, std::env::vars());
+    let mut problems = match value.as_table_mut() {
+        Some(table) => type_problems(table),
+        None => vec![],
+    };
+    match value.try_into::<types::Config>() {
+        Ok(config) => problems.extend(self::problems(&config)),
+        Err(err) => problems.push(err.to_string()),
+    }
+    Ok(problems)
+}
+
+fn read_file(filename: &str) -> error::Result<String> {
     let file = File::open(filename).context(error::FileOpenError { filename })?;
     let mut buf_reader = BufReader::new(file);
     let mut contents = String::new();
     buf_reader
         .read_to_string(&mut contents)
         .context(error::FileOpenError { filename })?;
-    let config = toml::from_str(&contents).context(error::TomlParsingError { filename })?;
-    Ok(config)
+    Ok(contents)
+}
+
+/// Removes the settings that do not deserialize from `table` and reports
+/// them, a section at a time so that one bad setting does not hide the
+/// others. Missing or removed uid and gid are set to root to check the rest.
+fn type_problems(table: &mut toml::value::Table) -> std::vec::Vec<String> {
+    let mut problems = vec![];
+    for key in &["uid", "gid"] {
+        if !table.contains_key(*key) {
+            problems.push(format!("{} is missing", key));
+        }
+    }
+    for key in table.keys().cloned().collect::<std::vec::Vec<_>>() {
+        if probe(&key, table[&key].clone()).is_ok() {
+            continue;
+        }
+        if let Some(section) = table[&key].as_table() {
+            let mut valid = section.clone();
+            let mut section_problems = vec![];
+            for (name, setting) in section {
+                let mut single = toml::value::Table::new();
+                single.insert(name.clone(), setting.clone());
+                if let Err(err) = probe(&key, toml::Value::Table(single)) {
+                    valid.remove(name);
+                    section_problems.push(err.to_string());
+                }
+            }
+            // settings valid alone may still be invalid together, like the
+            // fields of another mail transport
+            if probe(&key, toml::Value::Table(valid.clone())).is_ok() {
+                problems.extend(section_problems);
+                table.insert(key, toml::Value::Table(valid));
+                continue;
+            }
+        }
+        if let Err(err) = probe(&key, table[&key].clone()) {
+            problems.push(err.to_string());
+        }
+        table.remove(&key);
+    }
+    for key in &["uid", "gid"] {
+        table
+            .entry(key.to_string())
+            .or_insert(toml::Value::Integer(0));
+    }
+    problems
+}
+
+/// Deserializes a configuration with only `key` set besides uid and gid.
+fn probe(key: &str, setting: toml::Value) -> Result<types::Config, toml::de::Error> {
+    let mut table = toml::value::Table::new();
+    table.insert("uid".to_string(), toml::Value::Integer(0));
+    table.insert("gid".to_string(), toml::Value::Integer(0));
+    table.insert(key.to_string(), setting);
+    toml::Value::Table(table).try_into()
+}
+
+/// Checks what parsing alone cannot, before the daemon uses a configuration.
+pub fn validate(config: &types::Config) -> error::Result<()> {
+    let problems = problems(config);
+    if !problems.is_empty() {
+        return Err(error::Error::ConfigInvalid { problems });
+    }
+    Ok(())
+}
+
+/// Everything wrong with `config`, not only the first problem.
+pub fn problems(config: &types::Config) -> std::vec::Vec<String> {
+    let mut problems = vec![];
+    if users::get_user_by_uid(config.uid).is_none() {
+        problems.push(format!("User with uid {} does not exist", config.uid));
+    }
+    if users::get_group_by_gid(config.gid).is_none() {
+        problems.push(format!("Group with gid {} does not exist", config.gid));
+    }
+    check_absolute(&mut problems, "working_dir", Some(&config.working_dir));
+    check_absolute(&mut problems, "socket", Some(&config.socket));
+    check_absolute(&mut problems, "pid_file", config.pid_file.as_ref());
+    check_absolute(
+        &mut problems,
+        "db_password_file",
+        config.db_password_file.as_ref(),
+    );
+    check_absolute(&mut problems, "logging.file", config.logging.file.as_ref());
+    if let types::MailTransport::File { directory } = &config.mail_transport {
+        check_absolute(&mut problems, "mail_transport.directory", Some(directory));
+    }
+    if !Path::new(&config.working_dir).is_dir() {
+        problems.push(format!(
+            "Working directory {} does not exist",
+            config.working_dir
+        ));
+    }
+    if let Some(directory) = Path::new(&config.socket).parent() {
+        if !directory.is_dir() {
+            problems.push(format!(
+                "Directory {} of the socket does not exist",
+                directory.display()
+            ));
+        }
+    }
+    match storage::Backend::from_config(config) {
+        Ok(storage::Backend::MySql { .. }) => {}
+        Ok(_) if config.db_password_file.is_some() => {
+            problems.push("db_password_file is only used with MySQL".to_string())
+        }
+        Ok(_) => {}
+        Err(err) => problems.push(err.to_string()),
+    }
+    if let Some(password_file) = &config.db_password_file {
+        if !Path::new(password_file).is_file() {
+            problems.push(format!("Password file {} does not exist", password_file));
+        }
+    }
+    if let Err(err) = logging::level(&config.logging) {
+        problems.push(err.to_string());
+    }
+    if config.logging.destination == types::LogDestination::File && config.logging.file.is_none() {
+        problems.push(error::Error::LogFileMissing.to_string());
+    }
+    if config.db_pool.max_connections == 0
+        || config.db_pool.min_connections > config.db_pool.max_connections
+    {
+        problems.push(format!(
+            "Database pool needs 0 < min_connections <= max_connections, got {} and {}",
+            config.db_pool.min_connections, config.db_pool.max_connections
+        ));
+    }
+    if let types::MailTransport::Smtp {
+        timeout_seconds: 0, ..
+    } = config.mail_transport
+    {
+        problems.push("mail_transport.timeout_seconds must be positive".to_string());
+    }
+    if config.workers.threads == 0 {
+        problems.push("Need at least one worker thread".to_string());
+    }
+    // a queue of 0 would reject every connection no idle worker takes at once
+    if config.workers.queue_size == 0 {
+        problems.push("workers.queue_size must be positive".to_string());
+    }
+    // a timeout of 0 would let idle connections hold a worker forever
+    if config.workers.read_timeout_seconds == 0 {
+        problems.push("workers.read_timeout_seconds must be positive".to_string());
+    }
+    if config.workers.write_timeout_seconds == 0 {
+        problems.push("workers.write_timeout_seconds must be positive".to_string());
+    }
+    problems
+}
+
+fn check_absolute(problems: &mut std::vec::Vec<String>, setting: &str, path: Option<&String>) {
+    if let Some(path) = path {
+        if !Path::new(path).is_absolute() {
+            problems.push(format!("{} \"{}\" is not an absolute path", setting, path));
+        }
+    }
+}
+
+/// Sets the settings named by environment variables, values are parsed as
+/// TOML and taken as plain strings if that fails.
+fn apply_env_overrides(value: &mut toml::Value, vars: impl Iterator<Item = (String, String)>) {
+    for (name, setting) in vars {
+        let path = match name.strip_prefix(ENV_PREFIX) {
+            Some(path) if !path.is_empty() => path.to_lowercase(),
+            _ => continue,
+        };
+        let mut keys: std::vec::Vec<&str> = path.split("__").collect();
+        let last = keys.pop().unwrap();
+        let mut table = value.as_table_mut();
+        for key in keys {
+            table = table.and_then(|table| {
+                table
+                    .entry(key)
+                    .or_insert_with(|| toml::Value::Table(Default::default()))
+                    .as_table_mut()
+            });
+        }
+        if let Some(table) = table {
+            table.insert(last.to_string(), parse_env_value(&setting));
+        }
+    }
+}
+
+fn parse_env_value(setting: &str) -> toml::Value {
+    toml::from_str::<toml::value::Table>(&format!("value = {}", setting))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(setting.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types;
+
+    #[test]
+    fn environment_overrides_file() {
+        let mut value: toml::Value = toml::from_str(
+            r#"
+            db_url = "mysql://localhost/simplemm"
+            uid = 1000
+            gid = 1000
+            "#,
+        )
+        .unwrap();
+        let vars = vec![
+            ("SIMPLEMM_UID", "1001"),
+            ("SIMPLEMM_DB_URL", "memory://"),
+            ("SIMPLEMM_DB_POOL__MAX_CONNECTIONS", "3"),
+            ("OTHER_UID", "1002"),
+        ];
+        super::apply_env_overrides(
+            &mut value,
+            vars.into_iter()
+                .map(|(name, setting)| (name.to_string(), setting.to_string())),
+        );
+        let config: types::Config = value.try_into().unwrap();
+        assert_eq!(config.uid, 1001);
+        assert_eq!(config.gid, 1000);
+        assert_eq!(config.db_url, "memory://");
+        assert_eq!(config.db_pool.max_connections, 3);
+        assert_eq!(config.db_pool.min_connections, 1);
+        assert!(!config.send_rejections);
+    }
+
+    #[test]
+    fn reports_all_problems() {
+        let dir = tempfile::tempdir().unwrap();
+        let config: types::Config = toml::from_str(&format!(
+            r#"
+            db_url = "sqlite://simplemm.db"
+            db_password_file = "secret"
+            uid = 0
+            gid = 0
+            working_dir = "{}"
+            socket = "{}/missing/simplemmd.sock"
+            [workers]
+            queue_size = 0
+            read_timeout_seconds = 0
+            "#,
+            dir.path().display(),
+            dir.path().display()
+        ))
+        .unwrap();
+        let problems = super::problems(&config);
+        assert_eq!(problems.len(), 6, "{:?}", problems);
+        assert!(super::validate(&config).is_err());
+
+        let config = types::Config {
+            db_password_file: None,
+            socket: format!("{}/simplemmd.sock", dir.path().display()),
+            workers: Default::default(),
+            ..config
+        };
+        assert!(super::problems(&config).is_empty());
+    }
+
+    #[test]
+    fn checks_every_setting_of_the_wrong_type() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = dir.path().join("simplemm.conf");
+        std::fs::write(
+            &filename,
+            format!(
+                r#"
+                uid = "daemon"
+                socket = "{dir}/simplemm.sock"
+                working_dir = "{dir}"
+                template_dir = "{dir}"
+                db_url = "memory://"
+                send_rejections = "yes"
+
+                [workers]
+                threads = "many"
+                queue_size = -1
+                read_timeout_seconds = 0
+
+                [mail_transport]
+                type = "carrier-pigeon"
+                "#,
+                dir = dir.path().display()
+            ),
+        )
+        .unwrap();
+        let problems = super::check_file(filename.to_str().unwrap()).unwrap();
+        assert_eq!(problems.len(), 7, "{:?}", problems);
+        assert!(problems.iter().any(|p| p.contains("workers.threads")));
+        assert!(problems.iter().any(|p| p.contains("workers.queue_size")));
+        assert!(problems.iter().any(|p| p.contains("send_rejections")));
+        assert!(problems.iter().any(|p| p.contains("carrier-pigeon")));
+        assert!(problems.contains(&"gid is missing".to_string()));
+
+        std::fs::write(&filename, "uid = [").unwrap();
+        let problems = super::check_file(filename.to_str().unwrap()).unwrap();
+        assert_eq!(problems.len(), 1);
+    }
 }
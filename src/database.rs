@@ -1,71 +1,150 @@
-use crate::{error, state, types};
-use mysql::{params, prelude::Queryable};
+use crate::storage::{Backend, Storage};
+use crate::{address, error, state, types};
 use snafu::ResultExt;
 
-pub fn check_database(config: &types::Config) -> error::Result<()> {
-    let _ = mysql::Pool::new(&config.db_url).context(error::DbConnectionError {})?;
-    Ok(())
+/// Opens the storage for the daemon, if its schema version matches.
+pub fn open_database(config: &types::Config) -> error::Result<Box<dyn Storage>> {
+    Backend::from_config(config)?.open_checked()
+}
+
+/// Brings the schema of the configured database up to date, returns the
+/// applied migrations. A new SQLite file is handed to the daemon user.
+pub fn migrate(config: &types::Config) -> error::Result<std::vec::Vec<u32>> {
+    let backend = Backend::from_config(config)?;
+    let applied = backend.open()?.migrate()?;
+    if let Backend::Sqlite(path) = &backend {
+        if users::get_effective_uid() == 0 {
+            std::os::unix::fs::chown(path, Some(config.uid), Some(config.gid)).context(
+                error::DbFileOwnerError {
+                    path: path.to_string_lossy().to_string(),
+                },
+            )?;
+        }
+    }
+    Ok(applied)
 }
 
 pub fn insert_subscriptions(
     list_name: &str,
     subscriptions: std::vec::Vec<types::Subscription>,
     request: &str,
-    process_subscription: fn(&types::Subscription) -> error::Result<()>,
-) -> error::Result<()> {
+    process_subscription: fn(
+        &types::MailingList,
+        &types::Subscription,
+        types::SubscriptionOutcome,
+    ) -> error::Result<()>,
+) -> error::Result<std::vec::Vec<types::SubscriptionResult>> {
     let state = state::get_server_state()?;
-    let config = state.config;
-
-    let pool = mysql::Pool::new(&config.db_url).context(error::DbConnectionError {})?;
-    let mut connection = pool.get_conn().context(error::DbConnectionError {})?;
-    let mut transaction = connection
-        .start_transaction(mysql::TxOpts::default())
-        .context(error::DbStartTransactionError {})?;
-    let get_list_stmt = r"SELECT id FROM mailing_lists WHERE email = :email";
-    let prep_get_list_stmt = transaction
-        .prep(get_list_stmt)
-        .context(error::DbPrepareError {
-            statement: get_list_stmt,
-        })?;
-    let list_id: i32 = transaction
-        .exec_first(&prep_get_list_stmt, params! { "email" => list_name })
-        .context(error::DbExecuteError {
-            statement: get_list_stmt,
-        })?
-        .ok_or(error::Error::DbMailingListDoesNotExist {
-            list_name: list_name.to_string(),
-        })?;
-    let insert_statement = r"INSERT INTO subscriptions (uuid, list_id, email, request)
-                           VALUES (:uuid, :list_id, :email, :request)";
-
-    let insert_result = transaction.exec_batch(
-        insert_statement,
-        subscriptions.iter().map(|s| {
-            params! { "uuid" => s.uuid.clone(),
-                                            "list_id" => list_id,
-                                            "email" => s.email.clone(),
-                                            "request" => request,
-            }
-        }),
-    );
-    if let Err(err) = insert_result {
-        transaction
-            .rollback()
-            .context(error::DbRollbackTransactionError {})?;
-        return Err(err).context(error::DbExecuteError {
-            statement: insert_statement,
-        });
-    }
-    for subscription in subscriptions.iter() {
-        if let Err(err) = process_subscription(&subscription) {
-            transaction
-                .rollback()
-                .context(error::DbRollbackTransactionError {})?;
+    let storage = storage()?;
+    let (list, stored) = storage.insert_subscriptions(
+        list_name,
+        subscriptions,
+        request,
+        state.config.confirmation_cooldown_minutes,
+        state.config.subscription_expiry_hours,
+    )?;
+    // after the commit, a slow mail server must not hold up the storage
+    for (index, (subscription, outcome)) in stored.iter().enumerate() {
+        if let Err(err) = process_subscription(&list, subscription, *outcome) {
+            let unsent: std::vec::Vec<uuid::Uuid> = stored[index..]
+                .iter()
+                .filter(|(_, outcome)| {
+                    matches!(
+                        outcome,
+                        types::SubscriptionOutcome::Requested | types::SubscriptionOutcome::Renewed
+                    )
+                })
+                .map(|(subscription, _)| subscription.uuid)
+                .collect();
+            storage.discard_subscriptions(&unsent)?;
             return Err(err);
         }
     }
-    transaction
-        .commit()
-        .context(error::DbCommitTransactionError {})?;
-    Ok(())
+    Ok(stored
+        .into_iter()
+        .map(|(subscription, outcome)| types::SubscriptionResult {
+            email: subscription.email.original,
+            outcome,
+        })
+        .collect())
+}
+
+pub fn get_list(list_name: &str) -> error::Result<types::MailingList> {
+    storage()?.get_list(list_name)
+}
+
+pub fn create_list(list_name: &str, settings: &types::ListSettings) -> error::Result<()> {
+    storage()?.create_list(list_name, settings)
+}
+
+pub fn update_list(list_name: &str, settings: &types::ListSettings) -> error::Result<()> {
+    storage()?.update_list(list_name, settings)
+}
+
+pub fn set_list_enabled(list_name: &str, enabled: bool) -> error::Result<()> {
+    update_list(
+        list_name,
+        &types::ListSettings {
+            enabled: Some(enabled),
+            ..Default::default()
+        },
+    )
+}
+
+pub fn delete_list(list_name: &str) -> error::Result<()> {
+    storage()?.delete_list(list_name)
+}
+
+pub fn list_members(
+    list_name: &str,
+    query: &types::MemberQuery,
+) -> error::Result<types::MemberPage> {
+    storage()?.list_members(list_name, query)
+}
+
+pub fn add_member(
+    list_name: &str,
+    email: &address::Address,
+    enabled: bool,
+) -> error::Result<types::MailingList> {
+    storage()?.add_member(list_name, email, enabled)
+}
+
+pub fn remove_member(list_name: &str, email: &address::Address) -> error::Result<()> {
+    storage()?.update_member(list_name, email, None)
+}
+
+pub fn set_member_enabled(
+    list_name: &str,
+    email: &address::Address,
+    enabled: bool,
+) -> error::Result<()> {
+    storage()?.update_member(list_name, email, Some(enabled))
+}
+
+pub fn get_subscription(
+    token: &uuid::Uuid,
+) -> error::Result<(types::MailingList, types::Subscription)> {
+    let state = state::get_server_state()?;
+    let expiry_hours = state.config.subscription_expiry_hours;
+    storage()?.get_subscription(token, expiry_hours)
+}
+
+pub fn confirm_subscription(
+    token: &uuid::Uuid,
+) -> error::Result<(types::MailingList, types::Subscription)> {
+    let state = state::get_server_state()?;
+    let expiry_hours = state.config.subscription_expiry_hours;
+    storage()?.confirm_subscription(token, expiry_hours)
+}
+
+pub fn get_post_recipients(
+    list_name: &str,
+    sender: &address::Address,
+) -> error::Result<(types::MailingList, std::vec::Vec<String>)> {
+    storage()?.get_post_recipients(list_name, sender)
+}
+
+fn storage() -> error::Result<std::sync::Arc<dyn Storage>> {
+    state::get_storage()
 }
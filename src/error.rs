@@ -1,3 +1,4 @@
+use serde::{Deserialize, Serialize};
 use snafu::Snafu;
 
 #[derive(Debug, Snafu)]
@@ -13,6 +14,13 @@ pub enum Error {
         filename: String,
         source: toml::de::Error,
     },
+    #[snafu(display("Invalid configuration: {}", problems.join("; ")))]
+    ConfigInvalid { problems: std::vec::Vec<String> },
+    #[snafu(display("Could not read database password file {}: {}", path, source))]
+    DbPasswordFileError {
+        path: String,
+        source: std::io::Error,
+    },
     #[snafu(display("Could not reach database: {}", source))]
     DbConnectionError { source: mysql::Error },
     #[snafu(display("Could not prepare statement \"{}\": \"{}\"", statement, source))]
@@ -25,6 +33,46 @@ pub enum Error {
         statement: &'static str,
         source: mysql::Error,
     },
+    #[snafu(display("Invalid database url: {}", source))]
+    DbUrlError { source: mysql::UrlError },
+    #[snafu(display("Database is not opened"))]
+    DbNotOpened,
+    #[snafu(display("Unsupported database url scheme \"{}\"", scheme))]
+    DbUnsupportedUrl { scheme: String },
+    #[snafu(display(
+        "Database schema version {} is older than version {} of this release, \
+         run `simplemmd --migrate` or `simplemmclnt migrate`",
+        found,
+        expected
+    ))]
+    DbSchemaOutdated { found: u32, expected: u32 },
+    #[snafu(display(
+        "Database schema version {} is newer than version {} of this release",
+        found,
+        expected
+    ))]
+    DbSchemaTooNew { found: u32, expected: u32 },
+    #[snafu(display(
+        "Database migration cannot normalize \"{}\" to \"{}\", it is taken",
+        address,
+        normalized
+    ))]
+    DbMigrationConflict { address: String, normalized: String },
+    #[snafu(display("Could not change owner of database {}: {}", path, source))]
+    DbFileOwnerError {
+        path: String,
+        source: std::io::Error,
+    },
+    #[snafu(display("Could not open SQLite database {}: {}", path, source))]
+    SqliteOpenError {
+        path: String,
+        source: rusqlite::Error,
+    },
+    #[snafu(display("Could not execute statement \"{}\": \"{}\"", statement, source))]
+    SqliteExecuteError {
+        statement: &'static str,
+        source: rusqlite::Error,
+    },
     #[snafu(display("Could not start transaction: \"{}\"", source))]
     DbStartTransactionError { source: mysql::Error },
     #[snafu(display("Could not rollback transaction: \"{}\"", source))]
@@ -37,6 +85,35 @@ pub enum Error {
     SyslogError { source: syslog::Error },
     #[snafu(display("Error when initializing the logging subsystem: {}", source))]
     SetLoggerError { source: log::SetLoggerError },
+    #[snafu(display("Could not open log file {}: {}", path, source))]
+    LogFileOpenError {
+        path: String,
+        source: std::io::Error,
+    },
+    #[snafu(display("Logging to a file needs a file name"))]
+    LogFileMissing,
+    #[snafu(display("Unknown log level \"{}\"", level))]
+    LogLevelError { level: String },
+    #[snafu(display("Could not drop privileges to uid {}, gid {}: {}", uid, gid, source))]
+    PrivilegeDropError {
+        uid: u32,
+        gid: u32,
+        source: std::io::Error,
+    },
+    #[snafu(display("Could not install signal handler: {}", source))]
+    SignalHandlerError { source: std::io::Error },
+    #[snafu(display("Could not use socket passed by the service manager: {}", reason))]
+    ListenFdsError { reason: String },
+    #[snafu(display("Could not send notification to {}: {}", socket, source))]
+    NotifyError {
+        socket: String,
+        source: std::io::Error,
+    },
+    #[snafu(display("Could not change to working directory {}: {}", path, source))]
+    WorkingDirError {
+        path: String,
+        source: std::io::Error,
+    },
     #[snafu(display("Path \"{}\" not writeable. Permission error?", path))]
     CouldNotWriteToFileOrDirectory { path: String },
     #[snafu(display("Path {} is not a file", path))]
@@ -60,32 +137,46 @@ pub enum Error {
     #[snafu(display("Could not read/write server state: {}", source))]
     ServerStateError { source: Box<dyn std::error::Error> },
     #[snafu(display(
-        "Could not read pid file \"{}\": {}. Server not running?",
-        filename,
+        "Could not connect to socket {}: {}. Server not running?",
+        socket,
         source
     ))]
-    PidFileReadError {
-        filename: String,
+    SocketConnectError {
+        socket: String,
         source: std::io::Error,
     },
+    #[snafu(display("Could not exchange data with peer: {}", source))]
+    ProtocolIoError { source: std::io::Error },
     #[snafu(display(
-        "Could not parse pid file \"{}\": {}. Server not running?",
-        filename,
-        source
+        "Peer does not speak the versioned protocol, \
+         simplemmd and simplemmclnt are from incompatible releases"
     ))]
-    PidFileParseError {
-        filename: String,
-        source: std::num::ParseIntError,
-    },
+    ProtocolLegacyPeer,
+    #[snafu(display("Peer did not start the connection with the protocol handshake"))]
+    ProtocolBadMagic,
     #[snafu(display(
-        "Could not connect to socket {}: {}. Server not running?",
-        socket,
-        source
+        "Incompatible protocol versions: ours is {}, peer (simplemm {}) speaks {}",
+        local,
+        remote_software,
+        remote
     ))]
-    SocketConnectError {
-        socket: String,
-        source: std::io::Error,
+    ProtocolVersionMismatch {
+        local: u32,
+        remote: u32,
+        remote_software: String,
     },
+    #[snafu(display("Frame of {} bytes exceeds the maximum frame size", size))]
+    ProtocolFrameTooLarge { size: u64 },
+    #[snafu(display("Could not read credentials of peer: {}", source))]
+    PeerCredentialsError { source: std::io::Error },
+    #[snafu(display("User with uid {} is not allowed to {}", uid, action))]
+    PermissionDenied { uid: u32, action: String },
+    #[snafu(display("Could not start worker thread: {}", source))]
+    WorkerSpawnError { source: std::io::Error },
+    #[snafu(display("Server is busy, retry later"))]
+    ServerBusy,
+    #[snafu(display("Server error ({:?}): {}", kind, message))]
+    ServerError { kind: ErrorKind, message: String },
     #[snafu(display("Could not change permissions of socket {}: {}", socket, source))]
     SocketPermissionError {
         socket: String,
@@ -96,12 +187,18 @@ pub enum Error {
         socket: String,
         source: std::io::Error,
     },
+    #[snafu(display("Invalid value \"{}\" for {}: {}", value, name, source))]
+    InvalidArgument {
+        name: &'static str,
+        value: String,
+        source: std::num::ParseIntError,
+    },
     #[snafu(display("Could not read data from stdin: {}", source))]
     ReadStdinError { source: std::io::Error },
     #[snafu(display("Could not parse mail: {}", source))]
     MailParseError { source: mailparse::MailParseError },
-    #[snafu(display("Subscription request without data"))]
-    SubscriptionRequestWithoutData,
+    #[snafu(display("{} request without data", request_type))]
+    SubscriptionRequestWithoutData { request_type: &'static str },
     #[snafu(display("Empty or missing {} header in request \"{:?}\"", header, request))]
     EmptyOrMissingHeader {
         header: &'static str,
@@ -112,6 +209,15 @@ pub enum Error {
         header: &'static str,
         request: String,
     },
+    #[snafu(display(
+        "Invalid address list at character {}: expected {}",
+        position,
+        expected
+    ))]
+    AddressParseError {
+        position: usize,
+        expected: &'static str,
+    },
     #[snafu(display("Request {} without list name: \"{:?}\"", request_type, request))]
     RequestWithoutListName {
         request_type: &'static str,
@@ -119,6 +225,214 @@ pub enum Error {
     },
     #[snafu(display("Mailing list {} does not exist in the database", list_name))]
     DbMailingListDoesNotExist { list_name: String },
+    #[snafu(display("Mailing list {} already exists in the database", list_name))]
+    DbMailingListAlreadyExists { list_name: String },
+    #[snafu(display("Mailing list {} is disabled", list_name))]
+    DbMailingListDisabled { list_name: String },
+    #[snafu(display("Invalid mailing list address \"{}\"", address))]
+    InvalidListAddress { address: String },
+    #[snafu(display("Invalid member address \"{}\"", address))]
+    InvalidMemberAddress { address: String },
+    #[snafu(display("The {} of \"{}\" is longer than {} octets", part, address, limit))]
+    AddressTooLong {
+        address: String,
+        part: &'static str,
+        limit: usize,
+    },
+    #[snafu(display("{} is not a member of {}", email, list_name))]
+    DbMemberDoesNotExist { email: String, list_name: String },
+    #[snafu(display("{} request without data", request_type))]
+    MemberRequestWithoutData { request_type: &'static str },
+    #[snafu(display("Invalid language \"{}\", expected a two letter code", language))]
+    InvalidLanguage { language: String },
+    #[snafu(display("Invalid list title {:?}, control characters are not allowed", title))]
+    InvalidListTitle { title: String },
+    #[snafu(display(
+        "Invalid {} {:?}, expected an https:// or mailto: URI without spaces and angle brackets",
+        setting,
+        url
+    ))]
+    InvalidListUrl { setting: &'static str, url: String },
+    #[snafu(display("Confirmation request without data"))]
+    ConfirmationRequestWithoutData,
+    #[snafu(display("Confirmation request without token: \"{:?}\"", request))]
+    ConfirmationRequestWithoutToken { request: String },
+    #[snafu(display("No pending subscription with token {}", token))]
+    SubscriptionDoesNotExist { token: String },
+    #[snafu(display("Subscription with token {} has expired", token))]
+    SubscriptionExpired { token: String },
+    #[snafu(display("Subscription with token {} has unknown action {}", token, action))]
+    SubscriptionUnknownAction { token: String, action: String },
+    #[snafu(display("Post request without data"))]
+    PostRequestWithoutData,
+    #[snafu(display("Sender {} is not an enabled member of {}", sender, list_name))]
+    PostSenderNotMember { sender: String, list_name: String },
+    #[snafu(display("Post to {} already passed through the list, dropping it", list_name))]
+    PostLoopDetected { list_name: String },
+    #[snafu(display("Unknown template \"{}\"", name))]
+    UnknownTemplate { name: String },
+    #[snafu(display("Could not read template file {}: {}", path, source))]
+    TemplateReadError {
+        path: String,
+        source: std::io::Error,
+    },
+    #[snafu(display("Could not render template {}: {}", template, source))]
+    TemplateRenderError {
+        template: &'static str,
+        source: Box<handlebars::TemplateRenderError>,
+    },
+    #[snafu(display("Template {} does not start with a Subject: line", template))]
+    TemplateWithoutSubject { template: &'static str },
+    #[snafu(display("Render template request without template name"))]
+    RenderTemplateRequestWithoutName,
+    #[snafu(display("Could not run sendmail command \"{}\": {}", command, source))]
+    SendmailError {
+        command: String,
+        source: std::io::Error,
+    },
+    #[snafu(display("Sendmail command \"{}\" failed: {}", command, status))]
+    SendmailExitError {
+        command: String,
+        status: std::process::ExitStatus,
+    },
+    #[snafu(display("Could not talk to SMTP server {}: {}", server, source))]
+    SmtpIoError {
+        server: String,
+        source: std::io::Error,
+    },
+    #[snafu(display(
+        "SMTP server {} answered \"{}\", expected {}",
+        server,
+        response,
+        expected
+    ))]
+    SmtpResponseError {
+        server: String,
+        expected: u16,
+        response: String,
+    },
+    #[snafu(display("Could not write mail to file {}: {}", path, source))]
+    MailFileError {
+        path: String,
+        source: std::io::Error,
+    },
+    #[snafu(display("Could not serialize mail: {}", source))]
+    MailSerializeError { source: serde_json::Error },
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum ErrorKind {
+    Configuration,
+    Database,
+    NotFound,
+    AlreadyExists,
+    Disabled,
+    Expired,
+    Rejected,
+    InvalidRequest,
+    Incompatible,
+    PermissionDenied,
+    Busy,
+    Template,
+    MailDelivery,
+    Internal,
+}
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::FileOpenError { .. }
+            | Error::TomlParsingError { .. }
+            | Error::ConfigInvalid { .. }
+            | Error::DbPasswordFileError { .. }
+            | Error::DbUrlError { .. }
+            | Error::DbUnsupportedUrl { .. }
+            | Error::LogFileMissing
+            | Error::LogLevelError { .. } => ErrorKind::Configuration,
+            Error::DbConnectionError { .. }
+            | Error::DbPrepareError { .. }
+            | Error::DbExecuteError { .. }
+            | Error::DbStartTransactionError { .. }
+            | Error::DbRollbackTransactionError { .. }
+            | Error::DbCommitTransactionError { .. }
+            | Error::DbFileOwnerError { .. }
+            | Error::DbMigrationConflict { .. }
+            | Error::SqliteOpenError { .. }
+            | Error::SqliteExecuteError { .. } => ErrorKind::Database,
+            Error::DbMailingListDoesNotExist { .. }
+            | Error::DbMemberDoesNotExist { .. }
+            | Error::SubscriptionDoesNotExist { .. }
+            | Error::UnknownTemplate { .. } => ErrorKind::NotFound,
+            Error::DbMailingListAlreadyExists { .. } => ErrorKind::AlreadyExists,
+            Error::DbMailingListDisabled { .. } => ErrorKind::Disabled,
+            Error::SubscriptionExpired { .. } => ErrorKind::Expired,
+            Error::PostSenderNotMember { .. } | Error::PostLoopDetected { .. } => {
+                ErrorKind::Rejected
+            }
+            Error::ProtocolLegacyPeer
+            | Error::ProtocolBadMagic
+            | Error::ProtocolVersionMismatch { .. }
+            | Error::DbSchemaOutdated { .. }
+            | Error::DbSchemaTooNew { .. } => ErrorKind::Incompatible,
+            Error::PermissionDenied { .. } => ErrorKind::PermissionDenied,
+            Error::ServerBusy => ErrorKind::Busy,
+            Error::RequestParseError { .. }
+            | Error::ProtocolFrameTooLarge { .. }
+            | Error::MailParseError { .. }
+            | Error::SubscriptionRequestWithoutData { .. }
+            | Error::ConfirmationRequestWithoutData
+            | Error::ConfirmationRequestWithoutToken { .. }
+            | Error::PostRequestWithoutData
+            | Error::RenderTemplateRequestWithoutName
+            | Error::MemberRequestWithoutData { .. }
+            | Error::EmptyOrMissingHeader { .. }
+            | Error::CouldNotParseHeader { .. }
+            | Error::AddressParseError { .. }
+            | Error::RequestWithoutListName { .. }
+            | Error::InvalidListAddress { .. }
+            | Error::InvalidMemberAddress { .. }
+            | Error::AddressTooLong { .. }
+            | Error::InvalidLanguage { .. }
+            | Error::InvalidListTitle { .. }
+            | Error::InvalidListUrl { .. }
+            | Error::InvalidArgument { .. } => ErrorKind::InvalidRequest,
+            Error::TemplateReadError { .. }
+            | Error::TemplateRenderError { .. }
+            | Error::TemplateWithoutSubject { .. } => ErrorKind::Template,
+            Error::SendmailError { .. }
+            | Error::SendmailExitError { .. }
+            | Error::SmtpIoError { .. }
+            | Error::SmtpResponseError { .. }
+            | Error::MailFileError { .. }
+            | Error::MailSerializeError { .. } => ErrorKind::MailDelivery,
+            Error::ServerError { kind, .. } => *kind,
+            _ => ErrorKind::Internal,
+        }
+    }
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[cfg(test)]
+mod tests {
+    use super::{Error, ErrorKind};
+    use crate::types;
+
+    #[test]
+    fn error_response_carries_kind() {
+        let err = Error::DbMailingListDoesNotExist {
+            list_name: "gemuese@example.com".to_string(),
+        };
+        let response = types::Response::error(&err);
+        assert_eq!(response.status, types::Status::Error);
+        assert_eq!(response.error_kind, Some(ErrorKind::NotFound));
+        assert_eq!(
+            response.message,
+            "Mailing list gemuese@example.com does not exist in the database"
+        );
+
+        let json = serde_json::to_string(&response).unwrap();
+        let response: types::Response = serde_json::from_str(&json).unwrap();
+        assert_eq!(response.error_kind, Some(ErrorKind::NotFound));
+    }
+}
@@ -5,19 +5,20 @@ use std::path::{Path, PathBuf};
 
 pub fn check_working_dir(config: &types::Config) -> error::Result<()> {
     let path = Path::new(&config.working_dir);
-    check_writeable(&path)?;
+    check_writeable(path)?;
     Ok(())
 }
 
 pub fn check_pid_file(config: &types::Config) -> error::Result<()> {
-    let path = Path::new(&config.pid_file);
-    check_writeable_file(&path)?;
+    if let Some(pid_file) = &config.pid_file {
+        check_writeable_file(Path::new(pid_file))?;
+    }
     Ok(())
 }
 
 pub fn delete_file(file_path: &str) {
     let path = Path::new(&file_path);
-    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(path);
 }
 
 fn check_writeable_file(path: &Path) -> error::Result<()> {
@@ -1,9 +1,22 @@
+pub mod access;
+pub mod address;
 pub mod client;
 pub mod config;
 pub mod database;
 pub mod error;
 pub mod file;
+pub mod logging;
+pub mod mail;
 pub mod parse_mail;
+pub mod protocol;
 pub mod request;
+pub mod shutdown;
 pub mod state;
+pub mod stats;
+pub mod storage;
+pub mod systemd;
+pub mod template;
+#[cfg(test)]
+mod testing;
 pub mod types;
+pub mod workers;
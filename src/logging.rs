@@ -0,0 +1,180 @@
+use crate::{error, types};
+use snafu::ResultExt;
+use std::cell::RefCell;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+type SyslogLogger = syslog::Logger<syslog::LoggerBackend, syslog::Formatter3164>;
+
+thread_local! {
+    static FIELDS: RefCell<std::vec::Vec<(&'static str, String)>> = const { RefCell::new(vec![]) };
+}
+
+/// Key/value fields appended to every record this thread logs while the
+/// guard is alive.
+pub struct Fields {
+    count: usize,
+}
+
+struct Logger {
+    sink: Sink,
+    level: log::LevelFilter,
+}
+
+enum Sink {
+    Stderr,
+    File(Mutex<std::fs::File>),
+    Syslog(Mutex<SyslogLogger>),
+}
+
+/// Installs the logger of the daemon, before it forks so that a log file
+/// can still be opened with the privileges of the caller.
+pub fn initialize(config: &types::LogConfig) -> error::Result<()> {
+    let level = level(config)?;
+    let sink = match config.destination {
+        types::LogDestination::Stderr => Sink::Stderr,
+        types::LogDestination::File => {
+            let path = config.file.as_ref().ok_or(error::Error::LogFileMissing)?;
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .context(error::LogFileOpenError { path })?;
+            Sink::File(Mutex::new(file))
+        }
+        types::LogDestination::Syslog => {
+            let formatter = syslog::Formatter3164 {
+                facility: syslog::Facility::LOG_USER,
+                hostname: None,
+                process: "simplemmd".into(),
+                pid: 0,
+            };
+            let logger = syslog::unix(formatter).context(error::SyslogError {})?;
+            Sink::Syslog(Mutex::new(logger))
+        }
+    };
+    log::set_boxed_logger(Box::new(Logger { sink, level }))
+        .map(|()| log::set_max_level(level))
+        .context(error::SetLoggerError {})?;
+    Ok(())
+}
+
+pub fn level(config: &types::LogConfig) -> error::Result<log::LevelFilter> {
+    log::LevelFilter::from_str(&config.level).map_err(|_| error::Error::LogLevelError {
+        level: config.level.clone(),
+    })
+}
+
+/// Adds `fields` to the records of this thread until the guard is dropped.
+pub fn fields(fields: std::vec::Vec<(&'static str, String)>) -> Fields {
+    let count = fields.len();
+    FIELDS.with(|current| current.borrow_mut().extend(fields));
+    Fields { count }
+}
+
+impl Drop for Fields {
+    fn drop(&mut self) {
+        FIELDS.with(|current| {
+            let mut current = current.borrow_mut();
+            let len = current.len().saturating_sub(self.count);
+            current.truncate(len);
+        });
+    }
+}
+
+/// The fields of this thread as ` key=value` pairs, values with blanks or
+/// quotes are quoted.
+fn format_fields() -> String {
+    FIELDS.with(|current| {
+        current
+            .borrow()
+            .iter()
+            .map(|(key, value)| {
+                if value.is_empty()
+                    || value.contains(|c: char| c.is_whitespace() || c == '"' || c == '=')
+                {
+                    format!(" {}={:?}", key, value)
+                } else {
+                    format!(" {}={}", key, value)
+                }
+            })
+            .collect()
+    })
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let message = format!("{}{}", record.args(), format_fields());
+        match &self.sink {
+            Sink::Stderr => eprintln!("{}", format_line(record.level(), &message)),
+            Sink::File(file) => {
+                let mut file = file.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                let _ = writeln!(file, "{}", format_line(record.level(), &message));
+            }
+            Sink::Syslog(logger) => {
+                let mut logger = logger
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner());
+                let _ = match record.level() {
+                    log::Level::Error => logger.err(message),
+                    log::Level::Warn => logger.warning(message),
+                    log::Level::Info => logger.info(message),
+                    log::Level::Debug | log::Level::Trace => logger.debug(message),
+                };
+            }
+        }
+    }
+
+    fn flush(&self) {
+        match &self.sink {
+            Sink::Stderr => {
+                let _ = std::io::stderr().flush();
+            }
+            Sink::File(file) => {
+                let _ = file
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .flush();
+            }
+            Sink::Syslog(_) => {}
+        }
+    }
+}
+
+fn format_line(level: log::Level, message: &str) -> String {
+    format!(
+        "{} {:<5} simplemmd[{}]: {}",
+        chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        level,
+        std::process::id(),
+        message
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn fields_are_appended_while_guard_lives() {
+        assert_eq!(super::format_fields(), "");
+        let _list = super::fields(vec![("list", "gemuese@example.com".to_string())]);
+        {
+            let _request = super::fields(vec![
+                ("action", "Subscribe".to_string()),
+                ("originator", "Max Mustermann".to_string()),
+            ]);
+            assert_eq!(
+                super::format_fields(),
+                " list=gemuese@example.com action=Subscribe originator=\"Max Mustermann\""
+            );
+        }
+        assert_eq!(super::format_fields(), " list=gemuese@example.com");
+    }
+}
@@ -0,0 +1,522 @@
+use crate::{address, error, stats, template, types};
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::io::{BufRead, BufReader, Write};
+
+#[derive(Serialize, Deserialize)]
+pub struct Mail {
+    pub envelope_from: String,
+    pub recipients: std::vec::Vec<String>,
+    pub message: String,
+}
+
+pub fn confirmation(
+    config: &types::Config,
+    list: &types::MailingList,
+    subscription: &types::Subscription,
+) -> error::Result<Mail> {
+    let data = serde_json::json!({
+        "subscription": {
+            "email": subscription.email.original,
+            "token": subscription.uuid,
+            "action": subscription.action.as_str(),
+            "unsubscribe": subscription.action == types::SubscriptionAction::Unsubscribe,
+        }
+    });
+    system_mail(
+        config,
+        list,
+        template::Template::Confirmation,
+        &subscription.email.delivery(),
+        data,
+    )
+}
+
+/// A welcome, goodbye or already-subscribed notice to a member, naming the
+/// address as its owner wrote it.
+pub fn notice(
+    config: &types::Config,
+    list: &types::MailingList,
+    template: template::Template,
+    email: &address::Address,
+) -> error::Result<Mail> {
+    let data = serde_json::json!({ "member": { "email": email.original } });
+    system_mail(config, list, template, &email.delivery(), data)
+}
+
+pub fn system_mail(
+    config: &types::Config,
+    list: &types::MailingList,
+    template: template::Template,
+    recipient: &str,
+    data: serde_json::Value,
+) -> error::Result<Mail> {
+    let rendered = template::render(config, list, template, data)?;
+    let mut headers = vec![
+        ("Reply-To", list.request_address()),
+        ("Auto-Submitted", "auto-generated".to_string()),
+    ];
+    headers.extend(list_headers(list));
+    let message = compose(
+        &list.request_address(),
+        recipient,
+        &rendered.subject,
+        &headers,
+        &rendered.body,
+    );
+    Ok(Mail {
+        envelope_from: list.request_address(),
+        recipients: vec![recipient.to_string()],
+        message,
+    })
+}
+
+pub fn rewrite_post(list: &types::MailingList, data: &str) -> error::Result<String> {
+    const REMOVED_HEADERS: &[&str] = &[
+        "return-path",
+        "delivered-to",
+        "x-original-to",
+        "sender",
+        "errors-to",
+        "precedence",
+        "x-loop",
+    ];
+    let (headers, body_offset) =
+        mailparse::parse_headers(data.as_bytes()).context(error::MailParseError {})?;
+    let mut message = String::new();
+    for header in headers.iter() {
+        let key = header.get_key().trim().to_lowercase();
+        if key == "x-loop" && header.get_value().trim().eq_ignore_ascii_case(&list.email) {
+            return Err(error::Error::PostLoopDetected {
+                list_name: list.email.clone(),
+            });
+        }
+        if REMOVED_HEADERS.contains(&key.as_str()) || key.starts_with("list-") {
+            continue;
+        }
+        message.push_str(&header.get_key());
+        message.push_str(": ");
+        message.push_str(String::from_utf8_lossy(header.get_value_raw()).trim_end());
+        message.push_str("\r\n");
+    }
+    for (name, value) in list_headers(list).iter() {
+        message.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    message.push_str(&format!("Sender: {}\r\n", list.bounce_address()));
+    message.push_str("Precedence: list\r\n");
+    message.push_str(&format!("X-Loop: {}\r\n", list.email));
+    message.push_str("\r\n");
+    message.push_str(&data[body_offset..]);
+    Ok(message)
+}
+
+pub fn list_headers(list: &types::MailingList) -> std::vec::Vec<(&'static str, String)> {
+    let list_label = list.email.replacen('@', ".", 1);
+    let mut help = format!("<mailto:{}?subject=help>", list.request_address());
+    if let Some(help_url) = &list.help_url {
+        help.push_str(&format!(", <{}>", help_url));
+    }
+    let mut unsubscribe = format!("<mailto:{}>", list.unsubscribe_address());
+    if let Some(unsubscribe_url) = &list.unsubscribe_url {
+        unsubscribe.push_str(&format!(", <{}>", unsubscribe_url));
+    }
+    let mut headers = vec![
+        (
+            "List-Id",
+            format!("{} <{}>", encode_phrase(&list.title), list_label),
+        ),
+        ("List-Post", format!("<mailto:{}>", list.email)),
+        ("List-Help", help),
+        (
+            "List-Subscribe",
+            format!("<mailto:{}>", list.subscribe_address()),
+        ),
+        ("List-Unsubscribe", unsubscribe),
+    ];
+    // RFC 8058 one-click unsubscription requires an HTTPS URI in List-Unsubscribe
+    if list
+        .unsubscribe_url
+        .as_ref()
+        .is_some_and(|url| url.starts_with("https://"))
+    {
+        headers.push((
+            "List-Unsubscribe-Post",
+            "List-Unsubscribe=One-Click".to_string(),
+        ));
+    }
+    if let Some(archive_url) = &list.archive_url {
+        headers.push(("List-Archive", format!("<{}>", archive_url)));
+    }
+    headers
+}
+
+pub fn compose(
+    from: &str,
+    to: &str,
+    subject: &str,
+    extra_headers: &[(&str, String)],
+    body: &str,
+) -> String {
+    let mut message = String::new();
+    message.push_str(&format!("From: {}\r\n", from));
+    message.push_str(&format!("To: {}\r\n", to));
+    message.push_str(&format!("Subject: {}\r\n", encode_header_value(subject)));
+    message.push_str(&format!("Date: {}\r\n", chrono::Utc::now().to_rfc2822()));
+    message.push_str(&format!(
+        "Message-ID: <{}@{}>\r\n",
+        uuid::Uuid::new_v4(),
+        domain_of(from)
+    ));
+    for (name, value) in extra_headers {
+        message.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    message.push_str("MIME-Version: 1.0\r\n");
+    message.push_str("Content-Type: text/plain; charset=utf-8\r\n");
+    message.push_str("Content-Transfer-Encoding: 8bit\r\n");
+    message.push_str("\r\n");
+    for line in body.lines() {
+        message.push_str(line);
+        message.push_str("\r\n");
+    }
+    message
+}
+
+pub fn deliver(transport: &types::MailTransport, mail: &Mail) -> error::Result<()> {
+    let _delivering = stats::delivering();
+    match transport {
+        types::MailTransport::Sendmail { command } => deliver_sendmail(command, mail),
+        types::MailTransport::Smtp {
+            host,
+            port,
+            timeout_seconds,
+        } => deliver_smtp(host, *port, *timeout_seconds, mail),
+        types::MailTransport::File { directory } => deliver_file(directory, mail),
+    }
+}
+
+fn deliver_sendmail(command: &str, mail: &Mail) -> error::Result<()> {
+    let mut child = std::process::Command::new(command)
+        .arg("-i")
+        .arg("-f")
+        .arg(&mail.envelope_from)
+        .arg("--")
+        .args(&mail.recipients)
+        .stdin(std::process::Stdio::piped())
+        .spawn()
+        .context(error::SendmailError { command })?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(mail.message.as_bytes())
+            .context(error::SendmailError { command })?;
+    }
+    let status = child.wait().context(error::SendmailError { command })?;
+    if !status.success() {
+        return Err(error::Error::SendmailExitError {
+            command: command.to_string(),
+            status,
+        });
+    }
+    Ok(())
+}
+
+fn deliver_smtp(host: &str, port: u16, timeout_seconds: u64, mail: &Mail) -> error::Result<()> {
+    let server = format!("{}:{}", host, port);
+    let timeout = std::time::Duration::from_secs(timeout_seconds);
+    let stream = smtp_connect(&server, timeout).context(error::SmtpIoError { server: &server })?;
+    let mut session = SmtpSession {
+        server: &server,
+        reader: BufReader::new(
+            stream
+                .try_clone()
+                .context(error::SmtpIoError { server: &server })?,
+        ),
+        writer: stream,
+    };
+    session.expect(220)?;
+    session.command(&format!("EHLO {}", domain_of(&mail.envelope_from)), 250)?;
+    session.command(&format!("MAIL FROM:<{}>", mail.envelope_from), 250)?;
+    for recipient in mail.recipients.iter() {
+        // 251: not local, the server forwards it
+        session.command_any(&format!("RCPT TO:<{}>", recipient), &[250, 251])?;
+    }
+    session.command("DATA", 354)?;
+    let mut data = String::new();
+    for line in mail.message.lines() {
+        if line.starts_with('.') {
+            data.push('.');
+        }
+        data.push_str(line);
+        data.push_str("\r\n");
+    }
+    data.push('.');
+    session.command(&data, 250)?;
+    session.command("QUIT", 221)?;
+    Ok(())
+}
+
+/// Tries every address of `server`, a stalled server cannot block a worker.
+fn smtp_connect(
+    server: &str,
+    timeout: std::time::Duration,
+) -> std::io::Result<std::net::TcpStream> {
+    use std::net::ToSocketAddrs;
+    let mut last_error = None;
+    for address in server.to_socket_addrs()? {
+        match std::net::TcpStream::connect_timeout(&address, timeout) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+                return Ok(stream);
+            }
+            Err(err) => last_error = Some(err),
+        }
+    }
+    Err(last_error
+        .unwrap_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no address found")))
+}
+
+struct SmtpSession<'a> {
+    server: &'a str,
+    reader: BufReader<std::net::TcpStream>,
+    writer: std::net::TcpStream,
+}
+
+impl<'a> SmtpSession<'a> {
+    fn command(&mut self, command: &str, expected: u16) -> error::Result<()> {
+        self.command_any(command, &[expected])
+    }
+
+    fn command_any(&mut self, command: &str, expected: &[u16]) -> error::Result<()> {
+        self.writer
+            .write_all(format!("{}\r\n", command).as_bytes())
+            .context(error::SmtpIoError {
+                server: self.server,
+            })?;
+        self.expect_any(expected)
+    }
+
+    fn expect(&mut self, expected: u16) -> error::Result<()> {
+        self.expect_any(&[expected])
+    }
+
+    fn expect_any(&mut self, expected: &[u16]) -> error::Result<()> {
+        loop {
+            let mut line = String::new();
+            self.reader
+                .read_line(&mut line)
+                .context(error::SmtpIoError {
+                    server: self.server,
+                })?;
+            let code = line.get(..3).and_then(|code| code.parse::<u16>().ok());
+            if code.filter(|code| expected.contains(code)).is_none() {
+                return Err(error::Error::SmtpResponseError {
+                    server: self.server.to_string(),
+                    expected: expected[0],
+                    response: line.trim_end().to_string(),
+                });
+            }
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn deliver_file(directory: &str, mail: &Mail) -> error::Result<()> {
+    let path = std::path::Path::new(directory).join(format!("{}.json", uuid::Uuid::new_v4()));
+    let file = std::fs::File::create(&path).context(error::MailFileError {
+        path: path.to_string_lossy().to_string(),
+    })?;
+    serde_json::to_writer_pretty(file, mail).context(error::MailSerializeError {})?;
+    Ok(())
+}
+
+fn domain_of(address: &str) -> &str {
+    address
+        .rfind('@')
+        .map_or("localhost", |pos| &address[pos + 1..])
+}
+
+fn encode_phrase(value: &str) -> String {
+    if value.is_ascii() {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        encode_header_value(value)
+    }
+}
+
+fn encode_header_value(value: &str) -> String {
+    if value.is_ascii() {
+        value.to_string()
+    } else {
+        format!("=?utf-8?B?{}?=", base64::encode(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{testing, types};
+
+    #[test]
+    fn file_transport_captures_confirmation() {
+        let directory = tempfile::tempdir().unwrap();
+        let subscription = types::Subscription {
+            email: testing::list().address("frank@example.org").unwrap(),
+            uuid: uuid::Uuid::parse_str("4b0d4a45-8c3e-4a0e-a2b0-2f3b1e6c3a55").unwrap(),
+            action: types::SubscriptionAction::Subscribe,
+        };
+        let config = testing::config(&format!(
+            r#"
+            [mail_transport]
+            type = "file"
+            directory = "{}"
+            "#,
+            directory.path().to_string_lossy()
+        ));
+        let mail = super::confirmation(&config, &testing::list(), &subscription).unwrap();
+        super::deliver(&config.mail_transport, &mail).unwrap();
+
+        let entries: std::vec::Vec<_> = std::fs::read_dir(directory.path()).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+        let file = std::fs::File::open(entries[0].as_ref().unwrap().path()).unwrap();
+        let captured: super::Mail = serde_json::from_reader(file).unwrap();
+        assert_eq!(captured.envelope_from, "gemuese-request@example.com");
+        assert_eq!(captured.recipients, vec!["frank@example.org"]);
+        assert!(captured
+            .message
+            .contains("Subject: confirm 4b0d4a45-8c3e-4a0e-a2b0-2f3b1e6c3a55\r\n"));
+        assert!(captured
+            .message
+            .contains("Reply-To: gemuese-request@example.com\r\n"));
+    }
+
+    #[test]
+    fn notices_go_to_the_encoded_domain() {
+        let config = testing::config("");
+        let email = testing::list().address("Jürgen@Bücher.example").unwrap();
+        let mail = super::notice(
+            &config,
+            &testing::list(),
+            crate::template::Template::Welcome,
+            &email,
+        )
+        .unwrap();
+        assert_eq!(mail.recipients, vec!["Jürgen@xn--bcher-kva.example"]);
+        assert!(mail
+            .message
+            .contains("the address Jürgen@Bücher.example is"));
+    }
+
+    #[test]
+    fn smtp_accepts_forwarding_and_times_out() {
+        use std::io::{BufRead, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            writer.write_all(b"220 localhost\r\n").unwrap();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 {
+                let reply: &[u8] = match line.trim_end() {
+                    command if command.starts_with("EHLO") => b"250-localhost\r\n250 8BITMIME\r\n",
+                    command if command.starts_with("RCPT") => b"251 will forward\r\n",
+                    "DATA" => b"354 go ahead\r\n",
+                    "." => b"250 queued\r\n",
+                    "QUIT" => b"221 bye\r\n",
+                    command if command.starts_with("MAIL") => b"250 ok\r\n",
+                    _ => b"",
+                };
+                writer.write_all(reply).unwrap();
+                line.clear();
+            }
+            // stalls the second session after the greeting
+            let (mut stalled, _) = listener.accept().unwrap();
+            stalled.write_all(b"220 localhost\r\n").unwrap();
+            std::thread::sleep(std::time::Duration::from_secs(2));
+        });
+        let mail = super::Mail {
+            envelope_from: "gemuese-request@example.com".to_string(),
+            recipients: vec!["frank@example.org".to_string()],
+            message: "Subject: test\r\n\r\n.hidden dot\r\n".to_string(),
+        };
+        super::deliver_smtp("127.0.0.1", port, 1, &mail).unwrap();
+        assert!(matches!(
+            super::deliver_smtp("127.0.0.1", port, 1, &mail),
+            Err(crate::error::Error::SmtpIoError { .. })
+        ));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn rewrites_post_headers() {
+        let post = "Return-Path: <frank@example.org>\r\n\
+                    From: Frank <frank@example.org>\r\n\
+                    List-Id: other <other.example.net>\r\n\
+                    Subject: Kohlrabi\r\n\
+                    \r\n\
+                    Hello list\r\n";
+        let message = super::rewrite_post(&testing::list(), post).unwrap();
+        assert!(message.starts_with("From: Frank <frank@example.org>\r\n"));
+        assert!(!message.contains("Return-Path"));
+        assert!(!message.contains("other.example.net"));
+        assert!(message.contains("List-Id: =?utf-8?B?R2Vtw7xzZQ==?= <gemuese.example.com>\r\n"));
+        assert!(message.contains("Sender: gemuese-bounces@example.com\r\n"));
+        assert!(message.ends_with("\r\n\r\nHello list\r\n"));
+
+        let looped = format!("X-Loop: gemuese@example.com\r\n{}", post);
+        assert!(super::rewrite_post(&testing::list(), &looped).is_err());
+    }
+
+    #[test]
+    fn list_headers() {
+        let mut list = testing::list();
+        list.title = "Gemuese".to_string();
+        list.unsubscribe_url = Some("https://example.com/unsubscribe/gemuese".to_string());
+        list.archive_url = Some("https://example.com/archive/gemuese".to_string());
+        let headers = super::list_headers(&list);
+        let header = |name| {
+            headers
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.as_str())
+        };
+        assert_eq!(header("List-Id"), Some("\"Gemuese\" <gemuese.example.com>"));
+        assert_eq!(header("List-Post"), Some("<mailto:gemuese@example.com>"));
+        assert_eq!(
+            header("List-Help"),
+            Some("<mailto:gemuese-request@example.com?subject=help>")
+        );
+        assert_eq!(
+            header("List-Subscribe"),
+            Some("<mailto:gemuese-subscribe@example.com>")
+        );
+        assert_eq!(
+            header("List-Unsubscribe"),
+            Some("<mailto:gemuese-unsubscribe@example.com>, <https://example.com/unsubscribe/gemuese>")
+        );
+        assert_eq!(
+            header("List-Unsubscribe-Post"),
+            Some("List-Unsubscribe=One-Click")
+        );
+        assert_eq!(
+            header("List-Archive"),
+            Some("<https://example.com/archive/gemuese>")
+        );
+
+        list.unsubscribe_url = None;
+        assert!(!super::list_headers(&list)
+            .iter()
+            .any(|(key, _)| *key == "List-Unsubscribe-Post"));
+    }
+
+    #[test]
+    fn encodes_non_ascii_subject() {
+        assert_eq!(super::encode_header_value("confirm"), "confirm");
+        assert_eq!(
+            super::encode_header_value("Gemüse"),
+            "=?utf-8?B?R2Vtw7xzZQ==?="
+        );
+    }
+}
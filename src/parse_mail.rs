@@ -1,22 +1,482 @@
-pub fn get_addresses_in_from_header(from_header: &str) -> std::vec::Vec<String> {
-    lazy_static::lazy_static! {
-        static ref REGEX : regex::Regex = regex::Regex::new("(?:[^<@\\s]*@[^\\s>,]*)|(?:\"[^\"@]*\"@[^\\s>,]*)").unwrap();
+use crate::error;
+
+/// A mailbox of an address header, the display name is decoded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mailbox {
+    pub display_name: Option<String>,
+    pub address: String,
+}
+
+/// Parses an RFC 5322 address-list with UTF-8 as of RFC 6532, like the
+/// value of a From header. Groups are flattened into their mailboxes,
+/// comments are dropped and the addresses come without folding white space.
+pub fn parse_address_list(input: &str) -> error::Result<std::vec::Vec<Mailbox>> {
+    let mut parser = Parser {
+        chars: input.chars().collect(),
+        pos: 0,
+    };
+    parser.address_list()
+}
+
+struct Parser {
+    chars: std::vec::Vec<char>,
+    pos: usize,
+}
+
+enum Word {
+    Atom(String),
+    Quoted(String),
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn error(&self, expected: &'static str) -> error::Error {
+        error::Error::AddressParseError {
+            position: self.pos,
+            expected,
+        }
+    }
+
+    fn expect(&mut self, c: char, expected: &'static str) -> error::Result<()> {
+        if self.peek() != Some(c) {
+            return Err(self.error(expected));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn address_list(&mut self) -> error::Result<std::vec::Vec<Mailbox>> {
+        let mut mailboxes = vec![];
+        let mut addresses = 0;
+        loop {
+            self.skip_cfws()?;
+            match self.peek() {
+                None => break,
+                // obs-addr-list allows empty elements
+                Some(',') => {
+                    self.pos += 1;
+                    continue;
+                }
+                _ => self.address(&mut mailboxes, true)?,
+            }
+            addresses += 1;
+            self.skip_cfws()?;
+            match self.peek() {
+                None => break,
+                Some(',') => self.pos += 1,
+                _ => return Err(self.error("\",\" or the end of the list")),
+            }
+        }
+        if addresses == 0 {
+            return Err(self.error("an address"));
+        }
+        Ok(mailboxes)
+    }
+
+    /// A mailbox or, if `allow_group`, a group of mailboxes.
+    fn address(
+        &mut self,
+        mailboxes: &mut std::vec::Vec<Mailbox>,
+        allow_group: bool,
+    ) -> error::Result<()> {
+        let start = self.pos;
+        let addr_spec_error = match self.addr_spec() {
+            Ok(address) => {
+                self.skip_cfws()?;
+                if matches!(self.peek(), None | Some(',') | Some(';')) {
+                    mailboxes.push(Mailbox {
+                        display_name: None,
+                        address,
+                    });
+                    return Ok(());
+                }
+                self.error("\",\" or the end of the list")
+            }
+            Err(err) => err,
+        };
+        // no bare addr-spec, "a@b" <c@d> ends up here too
+        self.pos = start;
+        self.name_addr_or_group(mailboxes, allow_group)
+            .map_err(|err| further(addr_spec_error, err))
+    }
+
+    fn name_addr_or_group(
+        &mut self,
+        mailboxes: &mut std::vec::Vec<Mailbox>,
+        allow_group: bool,
+    ) -> error::Result<()> {
+        let display_name = self.phrase()?;
+        match self.peek() {
+            Some('<') => {
+                let address = self.angle_addr()?;
+                mailboxes.push(Mailbox {
+                    display_name,
+                    address,
+                });
+            }
+            Some(':') if allow_group && display_name.is_some() => {
+                self.pos += 1;
+                self.group_list(mailboxes)?;
+            }
+            _ => return Err(self.error("an address, \"<\" or \":\"")),
+        }
+        Ok(())
+    }
+
+    fn group_list(&mut self, mailboxes: &mut std::vec::Vec<Mailbox>) -> error::Result<()> {
+        loop {
+            self.skip_cfws()?;
+            match self.peek() {
+                Some(';') => {
+                    self.pos += 1;
+                    return Ok(());
+                }
+                Some(',') => self.pos += 1,
+                None => return Err(self.error("\";\"")),
+                _ => {
+                    self.address(mailboxes, false)?;
+                    self.skip_cfws()?;
+                    match self.peek() {
+                        Some(',') => self.pos += 1,
+                        Some(';') => {}
+                        _ => return Err(self.error("\",\" or \";\"")),
+                    }
+                }
+            }
+        }
+    }
+
+    fn angle_addr(&mut self) -> error::Result<String> {
+        self.expect('<', "\"<\"")?;
+        self.skip_cfws()?;
+        if self.peek() == Some('@') {
+            self.obs_route()?;
+        }
+        let address = self.addr_spec()?;
+        self.skip_cfws()?;
+        self.expect('>', "\">\"")?;
+        Ok(address)
+    }
+
+    /// Skips an obsolete source route like `@a.example,@b.example:`.
+    fn obs_route(&mut self) -> error::Result<()> {
+        loop {
+            self.skip_cfws()?;
+            match self.peek() {
+                Some('@') => {
+                    self.pos += 1;
+                    self.domain()?;
+                }
+                Some(',') => self.pos += 1,
+                Some(':') => {
+                    self.pos += 1;
+                    return Ok(());
+                }
+                _ => return Err(self.error("\":\" after the route")),
+            }
+        }
+    }
+
+    fn addr_spec(&mut self) -> error::Result<String> {
+        let local_part = self.local_part()?;
+        self.skip_cfws()?;
+        self.expect('@', "\"@\"")?;
+        let domain = self.domain()?;
+        Ok(format!("{}@{}", local_part, domain))
+    }
+
+    /// A dot-atom or quoted string, or words separated by dots as obsolete
+    /// syntax allows.
+    fn local_part(&mut self) -> error::Result<String> {
+        let mut local_part = String::new();
+        loop {
+            self.skip_cfws()?;
+            match self.word()? {
+                Some(Word::Atom(atom)) => local_part.push_str(&atom),
+                Some(Word::Quoted(text)) => local_part.push_str(&quote(&text)),
+                None => return Err(self.error("a local part")),
+            }
+            self.skip_cfws()?;
+            if self.peek() != Some('.') {
+                return Ok(local_part);
+            }
+            self.pos += 1;
+            local_part.push('.');
+        }
+    }
+
+    fn domain(&mut self) -> error::Result<String> {
+        self.skip_cfws()?;
+        if self.peek() == Some('[') {
+            return self.domain_literal();
+        }
+        let mut domain = String::new();
+        loop {
+            self.skip_cfws()?;
+            let atom = self.atom();
+            if atom.is_empty() {
+                return Err(self.error("a domain"));
+            }
+            domain.push_str(&atom);
+            let end = self.pos;
+            self.skip_cfws()?;
+            if self.peek() != Some('.') {
+                self.pos = end;
+                return Ok(domain);
+            }
+            self.pos += 1;
+            domain.push('.');
+        }
+    }
+
+    fn domain_literal(&mut self) -> error::Result<String> {
+        self.expect('[', "\"[\"")?;
+        let mut literal = "[".to_string();
+        loop {
+            match self.peek() {
+                Some(']') => {
+                    self.pos += 1;
+                    literal.push(']');
+                    return Ok(literal);
+                }
+                Some(c) if c == '[' || c == '\\' || c.is_whitespace() => {
+                    return Err(self.error("\"]\""))
+                }
+                Some(c) => {
+                    self.pos += 1;
+                    literal.push(c);
+                }
+                None => return Err(self.error("\"]\"")),
+            }
+        }
+    }
+
+    /// The display name, adjacent encoded words are joined without space.
+    fn phrase(&mut self) -> error::Result<Option<String>> {
+        let mut name = String::new();
+        let mut previous_encoded = false;
+        loop {
+            self.skip_cfws()?;
+            if self.peek() == Some('.') && !name.is_empty() {
+                // obs-phrase, as in "John Q. Public"
+                self.pos += 1;
+                name.push('.');
+                previous_encoded = false;
+                continue;
+            }
+            let (text, encoded) = match self.word()? {
+                Some(Word::Atom(atom)) => match decode_encoded_word(&atom) {
+                    Some(decoded) => (decoded, true),
+                    None => (atom, false),
+                },
+                Some(Word::Quoted(text)) => (text, false),
+                None => break,
+            };
+            let adjacent_encoded = encoded && previous_encoded;
+            if !(name.is_empty() || adjacent_encoded) {
+                name.push(' ');
+            }
+            name.push_str(&text);
+            previous_encoded = encoded;
+        }
+        Ok(Some(name).filter(|name| !name.is_empty()))
     }
-    REGEX
-        .find_iter(from_header)
-        .map(|elem| elem.as_str().to_string())
-        .collect()
+
+    fn word(&mut self) -> error::Result<Option<Word>> {
+        if self.peek() == Some('"') {
+            return Ok(Some(Word::Quoted(self.quoted_string()?)));
+        }
+        let atom = self.atom();
+        if atom.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Word::Atom(atom)))
+    }
+
+    fn atom(&mut self) -> String {
+        let mut atom = String::new();
+        while let Some(c) = self.peek().filter(|c| is_atext(*c)) {
+            atom.push(c);
+            self.pos += 1;
+        }
+        atom
+    }
+
+    fn quoted_string(&mut self) -> error::Result<String> {
+        self.expect('"', "'\"'")?;
+        let mut text = String::new();
+        loop {
+            match self.peek() {
+                Some('"') => {
+                    self.pos += 1;
+                    return Ok(text);
+                }
+                Some('\\') => {
+                    self.pos += 1;
+                    match self.peek() {
+                        // line breaks cannot be quoted, only folded
+                        Some('\r') | Some('\n') => {}
+                        Some(c) => text.push(c),
+                        None => return Err(self.error("a quoted character")),
+                    }
+                    self.pos += 1;
+                }
+                // unfolding
+                Some('\r') | Some('\n') => self.pos += 1,
+                Some(c) => {
+                    text.push(c);
+                    self.pos += 1;
+                }
+                None => return Err(self.error("'\"'")),
+            }
+        }
+    }
+
+    /// Skips white space and nested comments.
+    fn skip_cfws(&mut self) -> error::Result<()> {
+        loop {
+            match self.peek() {
+                Some(c) if c.is_whitespace() => self.pos += 1,
+                Some('(') => self.skip_comment()?,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn skip_comment(&mut self) -> error::Result<()> {
+        let mut depth = 0;
+        loop {
+            match self.peek() {
+                Some('(') => depth += 1,
+                Some(')') => {
+                    depth -= 1;
+                    if depth == 0 {
+                        self.pos += 1;
+                        return Ok(());
+                    }
+                }
+                Some('\\') => self.pos += 1,
+                Some(_) => {}
+                None => return Err(self.error("\")\"")),
+            }
+            self.pos += 1;
+        }
+    }
+}
+
+/// Of two parse errors the one that got further, it is the more useful one.
+fn further(first: error::Error, second: error::Error) -> error::Error {
+    let position = |err: &error::Error| match err {
+        error::Error::AddressParseError { position, .. } => *position,
+        _ => 0,
+    };
+    if position(&second) >= position(&first) {
+        second
+    } else {
+        first
+    }
+}
+
+/// atext of RFC 5322 and the non-ASCII characters of RFC 6532.
+fn is_atext(c: char) -> bool {
+    c.is_ascii_alphanumeric()
+        || "!#$%&'*+-/=?^_`{|}~".contains(c)
+        || (!c.is_ascii() && !c.is_whitespace() && !c.is_control())
+}
+
+fn quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Decodes an RFC 2047 encoded word, None for an ordinary atom.
+fn decode_encoded_word(atom: &str) -> Option<String> {
+    if !(atom.starts_with("=?") && atom.ends_with("?=") && atom.len() > 4) {
+        return None;
+    }
+    let header = format!("X: {}", atom);
+    let (header, _) = mailparse::parse_header(header.as_bytes()).ok()?;
+    Some(header.get_value())
 }
 
 #[cfg(test)]
 mod tests {
+    use super::Mailbox;
+    use crate::error;
+
+    fn mailbox(display_name: Option<&str>, address: &str) -> Mailbox {
+        Mailbox {
+            display_name: display_name.map(str::to_string),
+            address: address.to_string(),
+        }
+    }
+
     #[test]
-    fn parse_mails() {
+    fn parse_address_lists() {
+        assert_eq!(
+            super::parse_address_list(
+                "foo@bar, Frank Rotzelpü <frank@göckel.com>, Murkel <\"my murkel\"@localhost>"
+            )
+            .unwrap(),
+            vec![
+                mailbox(None, "foo@bar"),
+                mailbox(Some("Frank Rotzelpü"), "frank@göckel.com"),
+                mailbox(Some("Murkel"), "\"my murkel\"@localhost"),
+            ]
+        );
         assert_eq!(
-            super::get_addresses_in_from_header(
-                "foo@bar, Frank Rotzelpü <frank@göckel.com>, Murkel <\"my murkel\"@localhost"
-            ),
-            std::vec!("foo@bar", "frank@göckel.com", "\"my murkel\"@localhost")
+            super::parse_address_list(
+                "Gemüse: alice(at work)@example.com, Bob <bob@example.com>;, (none) carol @ example.com"
+            )
+            .unwrap(),
+            vec![
+                mailbox(None, "alice@example.com"),
+                mailbox(Some("Bob"), "bob@example.com"),
+                mailbox(None, "carol@example.com"),
+            ]
         );
+        assert_eq!(
+            super::parse_address_list("undisclosed-recipients:;").unwrap(),
+            vec![]
+        );
+        assert_eq!(
+            super::parse_address_list(
+                "\"frank@evil.example.com\" <frank@example.com>, John Q. Public <@relay.example.com:john@example.com>"
+            )
+            .unwrap(),
+            vec![
+                mailbox(Some("frank@evil.example.com"), "frank@example.com"),
+                mailbox(Some("John Q. Public"), "john@example.com"),
+            ]
+        );
+        assert_eq!(
+            super::parse_address_list("=?utf-8?q?Fr=C3=A4nk?= =?utf-8?q?_R?= <frank@example.com>")
+                .unwrap(),
+            vec![mailbox(Some("Fränk R"), "frank@example.com")]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_lists() {
+        for (input, position) in &[
+            ("", 0),
+            ("Frank frank@evil.example.com <frank@example.com>", 11),
+            ("<frank@example.com", 18),
+            ("frank@", 6),
+            ("frank@example.com; bob@example.com", 17),
+            ("Gemüse: frank@example.com", 25),
+            ("(frank@example.com", 18),
+        ] {
+            match super::parse_address_list(input) {
+                Err(error::Error::AddressParseError {
+                    position: found, ..
+                }) => {
+                    assert_eq!(found, *position, "{}", input)
+                }
+                result => panic!("{}: {:?}", input, result.map_err(|err| err.to_string())),
+            }
+        }
     }
 }
@@ -0,0 +1,326 @@
+use crate::{error, state, types};
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::io::{Read, Write};
+
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const CAPABILITIES: &[&str] = &["responses", "multiple-requests"];
+
+static MAGIC: &[u8; 4] = b"SMMP";
+/// Responses, like the members of a large list.
+const MAX_FRAME_SIZE: u32 = 64 * 1024 * 1024;
+/// Commands carry at most a post, like the default size limit of Postfix.
+const MAX_COMMAND_SIZE: u32 = 10 * 1024 * 1024;
+/// The hello is read before the request is authorized.
+const MAX_HELLO_SIZE: u32 = 64 * 1024;
+
+#[derive(Serialize, Deserialize)]
+pub struct Hello {
+    pub protocol_version: u32,
+    pub software_version: String,
+    pub capabilities: std::vec::Vec<String>,
+}
+
+impl Hello {
+    pub fn new() -> Hello {
+        Hello {
+            protocol_version: PROTOCOL_VERSION,
+            software_version: state::get_server_version().to_string(),
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+}
+
+/// The server answers the hello of a peer it refuses with an error response.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Greeting {
+    Hello(Hello),
+    Refused(types::Response),
+}
+
+impl Default for Hello {
+    fn default() -> Hello {
+        Hello::new()
+    }
+}
+
+/// Sends the magic bytes and our hello, returns the hello of the server.
+pub fn client_handshake<S: Read + Write>(stream: &mut S) -> error::Result<Hello> {
+    let sent = stream
+        .write_all(MAGIC)
+        .context(error::ProtocolIoError {})
+        .and_then(|_| write_frame(stream, &Hello::new()));
+    // a refusing server may hang up before our hello is written, daemons
+    // without protocol versioning fail to parse the magic bytes and hang up
+    // or answer with plain JSON, other failures are no sign of age
+    let hello = match (read_frame_with_limit(stream, MAX_HELLO_SIZE), sent) {
+        (Ok(Some(Greeting::Refused(response))), _) => {
+            return Err(error::Error::ServerError {
+                kind: response.error_kind.unwrap_or(error::ErrorKind::Internal),
+                message: response.message,
+            })
+        }
+        (_, Err(err)) => return Err(err),
+        (Ok(Some(Greeting::Hello(hello))), _) => hello,
+        (Ok(None), _) => return Err(error::Error::ProtocolLegacyPeer),
+        (Err(error::Error::ProtocolFrameTooLarge { size }), _) if size >> 24 == u64::from(b'{') => {
+            return Err(error::Error::ProtocolLegacyPeer)
+        }
+        (Err(err), _) => return Err(err),
+    };
+    check_version(&hello)?;
+    Ok(hello)
+}
+
+/// Reads the magic bytes and the hello of the client and answers with our
+/// hello, also if the versions do not match, so the client can report it.
+pub fn server_handshake<S: Read + Write>(stream: &mut S) -> error::Result<Hello> {
+    let mut magic = [0u8; 4];
+    stream
+        .read_exact(&mut magic)
+        .context(error::ProtocolIoError {})?;
+    if &magic != MAGIC {
+        if magic[0] == b'{' {
+            return Err(error::Error::ProtocolLegacyPeer);
+        }
+        return Err(error::Error::ProtocolBadMagic);
+    }
+    let hello: Hello =
+        read_frame_with_limit(stream, MAX_HELLO_SIZE)?.ok_or(error::Error::ProtocolLegacyPeer)?;
+    write_frame(stream, &Hello::new())?;
+    check_version(&hello)?;
+    Ok(hello)
+}
+
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, value: &T) -> error::Result<()> {
+    write_frame_with_limit(writer, value, MAX_FRAME_SIZE)
+}
+
+/// Writes a command, the server refuses larger ones without reading them.
+pub fn write_command<W: Write>(writer: &mut W, command: &types::Command) -> error::Result<()> {
+    write_frame_with_limit(writer, command, MAX_COMMAND_SIZE)
+}
+
+fn write_frame_with_limit<W: Write, T: Serialize>(
+    writer: &mut W,
+    value: &T,
+    limit: u32,
+) -> error::Result<()> {
+    let payload = serde_json::to_vec(value).context(error::RequestSerializeError {})?;
+    if payload.len() > limit as usize {
+        return Err(error::Error::ProtocolFrameTooLarge {
+            size: payload.len() as u64,
+        });
+    }
+    writer
+        .write_all(&(payload.len() as u32).to_be_bytes())
+        .context(error::ProtocolIoError {})?;
+    writer
+        .write_all(&payload)
+        .context(error::ProtocolIoError {})?;
+    writer.flush().context(error::ProtocolIoError {})?;
+    Ok(())
+}
+
+/// Reads one frame, `None` if the peer closed the connection between frames.
+pub fn read_frame<R: Read, T: for<'de> Deserialize<'de>>(
+    reader: &mut R,
+) -> error::Result<Option<T>> {
+    read_frame_with_limit(reader, MAX_FRAME_SIZE)
+}
+
+/// Reads one command, a larger frame is refused before its payload is read.
+pub fn read_command<R: Read>(reader: &mut R) -> error::Result<Option<types::Command>> {
+    read_frame_with_limit(reader, MAX_COMMAND_SIZE)
+}
+
+fn read_frame_with_limit<R: Read, T: for<'de> Deserialize<'de>>(
+    reader: &mut R,
+    limit: u32,
+) -> error::Result<Option<T>> {
+    let mut length = [0u8; 4];
+    let mut read = 0;
+    while read < length.len() {
+        match reader.read(&mut length[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+                    .context(error::ProtocolIoError {})
+            }
+            Ok(n) => read += n,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err).context(error::ProtocolIoError {}),
+        }
+    }
+    let length = u32::from_be_bytes(length);
+    if length > limit {
+        return Err(error::Error::ProtocolFrameTooLarge {
+            size: u64::from(length),
+        });
+    }
+    let mut payload = vec![0u8; length as usize];
+    reader
+        .read_exact(&mut payload)
+        .context(error::ProtocolIoError {})?;
+    let value = serde_json::from_slice(&payload).context(error::RequestParseError {})?;
+    Ok(Some(value))
+}
+
+fn check_version(hello: &Hello) -> error::Result<()> {
+    if hello.protocol_version != PROTOCOL_VERSION {
+        return Err(error::Error::ProtocolVersionMismatch {
+            local: PROTOCOL_VERSION,
+            remote: hello.protocol_version,
+            remote_software: hello.software_version.clone(),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{error, types};
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn handshake_and_several_requests() {
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        let server = std::thread::spawn(move || {
+            let hello = super::server_handshake(&mut server).unwrap();
+            assert!(hello.has_capability("multiple-requests"));
+            let mut actions = 0;
+            while let Some(command) = super::read_frame::<_, types::Command>(&mut server).unwrap() {
+                assert_eq!(command.originator, "tester");
+                actions += 1;
+                super::write_frame(&mut server, &types::Response::ok(actions.to_string())).unwrap();
+            }
+            actions
+        });
+        super::client_handshake(&mut client).unwrap();
+        for expected in &["1", "2"] {
+            let command = types::Command {
+                action: types::Action::Alive,
+                originator: "tester".to_string(),
+                list_name: None,
+                data: None,
+            };
+            super::write_frame(&mut client, &command).unwrap();
+            let response: types::Response = super::read_frame(&mut client).unwrap().unwrap();
+            assert_eq!(&response.message, expected);
+        }
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        assert_eq!(server.join().unwrap(), 2);
+    }
+
+    #[test]
+    fn version_mismatch_is_reported_on_both_sides() {
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        let server = std::thread::spawn(move || {
+            matches!(
+                super::server_handshake(&mut server),
+                Err(error::Error::ProtocolVersionMismatch { .. })
+            )
+        });
+        let mut hello = super::Hello::new();
+        hello.protocol_version = super::PROTOCOL_VERSION + 1;
+        std::io::Write::write_all(&mut client, super::MAGIC).unwrap();
+        super::write_frame(&mut client, &hello).unwrap();
+        let answer: super::Hello = super::read_frame(&mut client).unwrap().unwrap();
+        assert_eq!(answer.protocol_version, super::PROTOCOL_VERSION);
+        assert!(server.join().unwrap());
+    }
+
+    #[test]
+    fn detects_unversioned_peers() {
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        std::io::Write::write_all(&mut client, b"{\"action\":\"Alive\"}").unwrap();
+        assert!(matches!(
+            super::server_handshake(&mut server),
+            Err(error::Error::ProtocolLegacyPeer)
+        ));
+
+        // hangs up after reading the request
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        let legacy = std::thread::spawn(move || {
+            let mut magic = [0u8; 4];
+            std::io::Read::read_exact(&mut server, &mut magic).unwrap();
+            super::read_frame::<_, super::Hello>(&mut server).unwrap();
+        });
+        assert!(matches!(
+            super::client_handshake(&mut client),
+            Err(error::Error::ProtocolLegacyPeer)
+        ));
+        legacy.join().unwrap();
+
+        // answers with JSON
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        std::io::Write::write_all(&mut server, b"{\"status\":\"error\"}").unwrap();
+        assert!(matches!(
+            super::client_handshake(&mut client),
+            Err(error::Error::ProtocolLegacyPeer)
+        ));
+    }
+
+    #[test]
+    fn refused_peers_get_the_reason() {
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        let err = error::Error::PermissionDenied {
+            uid: 1002,
+            action: "connect".to_string(),
+        };
+        super::write_frame(&mut server, &types::Response::error(&err)).unwrap();
+        drop(server);
+        assert!(matches!(
+            super::client_handshake(&mut client),
+            Err(error::Error::ServerError {
+                kind: error::ErrorKind::PermissionDenied,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn limits_size_of_commands() {
+        let mut frame = (super::MAX_COMMAND_SIZE + 1).to_be_bytes().to_vec();
+        frame.extend_from_slice(b"{}");
+        assert!(matches!(
+            super::read_command(&mut frame.as_slice()),
+            Err(error::Error::ProtocolFrameTooLarge { .. })
+        ));
+        let command = types::Command {
+            action: types::Action::Post,
+            originator: "tester".to_string(),
+            list_name: None,
+            data: Some("x".repeat(super::MAX_COMMAND_SIZE as usize)),
+        };
+        assert!(matches!(
+            super::write_command(&mut std::vec::Vec::new(), &command),
+            Err(error::Error::ProtocolFrameTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn reports_failures_of_current_peers() {
+        // a daemon that does not answer in time is busy, not old
+        let (mut client, _server) = UnixStream::pair().unwrap();
+        client
+            .set_read_timeout(Some(std::time::Duration::from_millis(50)))
+            .unwrap();
+        assert!(matches!(
+            super::client_handshake(&mut client),
+            Err(error::Error::ProtocolIoError { .. })
+        ));
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        drop(server);
+        assert!(matches!(
+            super::client_handshake(&mut client),
+            Err(error::Error::ProtocolIoError { .. })
+        ));
+    }
+}
@@ -1,69 +1,658 @@
-use crate::{database, error, parse_mail, state, types};
+use crate::{
+    access, address, database, error, logging, mail, parse_mail, protocol, shutdown, state, stats,
+    storage, template, types,
+};
 use snafu::ResultExt;
 use std::os::unix::net::UnixStream;
 
-pub fn process_request(command: types::Command, stream: UnixStream) {
-    let result = match command.action {
-        types::Action::Stop => {
-            state::stop_server();
-            Ok(())
+/// Serves the requests of one connection. `queued` keeps shutdown waiting
+/// until the first request is read, then each request is tracked on its own.
+pub fn handle_connection(
+    mut stream: UnixStream,
+    credentials: access::Credentials,
+    queued: shutdown::InFlight,
+) {
+    // nothing is read from peers without a role, they cannot make us allocate
+    if let Err(err) = refuse_without_role(&credentials) {
+        stats::count_refused();
+        log::warn!(
+            "Refused connection (uid {}, pid {}): {}",
+            credentials.uid,
+            credentials.pid,
+            err
+        );
+        let _ = protocol::write_frame(&mut stream, &types::Response::error(&err));
+        return;
+    }
+    if let Err(err) = protocol::server_handshake(&mut stream) {
+        log::warn!("Rejected connection: {}", err);
+        return;
+    }
+    let mut queued = Some(queued);
+    loop {
+        let read = protocol::read_command(&mut stream);
+        let stop = matches!(&read, Ok(Some(command)) if command.action == types::Action::Stop);
+        // stopping waits for all other requests, so it must not count itself
+        let _in_flight = if stop { None } else { Some(shutdown::track()) };
+        drop(queued.take());
+        let response = match read {
+            Ok(Some(mut command)) => {
+                command.list_name = command.list_name.map(|name| address::list_name(&name));
+                let _fields = logging::fields(log_fields(&command));
+                let response = match authorize(&credentials, &command) {
+                    Ok(()) => process_request(command, &credentials),
+                    Err(err) => {
+                        stats::count_refused();
+                        log::warn!(
+                            "Refused {:?} from {} (uid {}, pid {}): {}",
+                            command.action,
+                            command.originator,
+                            credentials.uid,
+                            credentials.pid,
+                            err
+                        );
+                        types::Response::error(&err)
+                    }
+                };
+                stats::count_request(&response);
+                if stop && response.status == types::Status::Ok {
+                    let _ = protocol::write_frame(&mut stream, &response);
+                    state::exit_server();
+                }
+                response
+            }
+            Ok(None) => break,
+            Err(err @ error::Error::ProtocolFrameTooLarge { .. }) => {
+                // the payload is not read, the connection cannot continue
+                log::warn!("Could not read request: {}", err);
+                let _ = protocol::write_frame(&mut stream, &types::Response::error(&err));
+                break;
+            }
+            Err(err @ error::Error::RequestParseError { .. }) => {
+                log::warn!("Could not parse request: {}", err);
+                types::Response::error(&err)
+            }
+            Err(err) => {
+                log::warn!("Could not read request: {}", err);
+                break;
+            }
+        };
+        if let Err(err) = protocol::write_frame(&mut stream, &response) {
+            log::warn!("Could not send response: {}", err);
+            break;
+        }
+        if shutdown::is_stopping() {
+            break;
         }
-        types::Action::Alive => signal_alive(stream),
-        types::Action::Subscribe => handle_subscribe(command),
-    };
-    if let Err(err) = result {
-        log::error!("Error handling request: {}", err);
     }
 }
 
-fn signal_alive(stream: UnixStream) -> error::Result<()> {
-    let state = state::get_server_state()?;
-    send_signal_alive(stream, &state)
+/// Identifies the request in everything logged while serving it.
+fn log_fields(command: &types::Command) -> std::vec::Vec<(&'static str, String)> {
+    let mut fields = vec![];
+    if let Some(list_name) = &command.list_name {
+        fields.push(("list", list_name.clone()));
+    }
+    fields.push(("action", format!("{:?}", command.action)));
+    fields.push(("originator", command.originator.clone()));
+    fields
 }
 
-fn send_signal_alive(stream: UnixStream, state: &types::DaemonState) -> error::Result<()> {
-    serde_json::to_writer(&stream, state).context(error::RequestSerializeError {})?;
-    let _ = stream.shutdown(std::net::Shutdown::Both);
+fn refuse_without_role(credentials: &access::Credentials) -> error::Result<()> {
+    let config = state::get_server_state()?.config;
+    if !access::roles(&config, credentials).any() {
+        return Err(error::Error::PermissionDenied {
+            uid: credentials.uid,
+            action: "connect".to_string(),
+        });
+    }
     Ok(())
 }
 
-fn handle_subscribe(command: types::Command) -> error::Result<()> {
-    use mailparse::MailHeaderMap;
+fn authorize(credentials: &access::Credentials, command: &types::Command) -> error::Result<()> {
+    let config = state::get_server_state()?.config;
+    access::authorize(&config, credentials, command)
+}
+
+/// Answers a connection that found the worker queue full instead of the
+/// handshake. Runs on the accepting thread, so nothing is read and the
+/// answer must fit into the socket buffer.
+pub fn reject_busy(mut stream: UnixStream) {
+    stats::count_rejected_busy();
+    let result = stream
+        .set_nonblocking(true)
+        .context(error::ProtocolIoError {})
+        .and_then(|_| {
+            protocol::write_frame(
+                &mut stream,
+                &types::Response::error(&error::Error::ServerBusy),
+            )
+        });
+    if let Err(err) = result {
+        log::warn!("Could not reject connection of busy server: {}", err);
+    }
+}
+
+pub fn process_request(
+    command: types::Command,
+    credentials: &access::Credentials,
+) -> types::Response {
+    match handle_request(command, credentials) {
+        Ok(response) => response,
+        Err(err) => {
+            log::error!("Error handling request: {}", err);
+            types::Response::error(&err)
+        }
+    }
+}
+
+fn handle_request(
+    command: types::Command,
+    credentials: &access::Credentials,
+) -> error::Result<types::Response> {
+    match command.action {
+        types::Action::Stop => stop_server(),
+        types::Action::Alive => signal_alive(credentials),
+        types::Action::Reload => reload_server(),
+        types::Action::Subscribe => {
+            handle_subscription_request(command, types::SubscriptionAction::Subscribe)
+        }
+        types::Action::Unsubscribe => {
+            handle_subscription_request(command, types::SubscriptionAction::Unsubscribe)
+        }
+        types::Action::Confirm => handle_confirm(command),
+        types::Action::Post => handle_post(command),
+        types::Action::RenderTemplate => render_template(command),
+        types::Action::CreateList
+        | types::Action::DeleteList
+        | types::Action::EnableList
+        | types::Action::DisableList
+        | types::Action::SetList => handle_list_admin(command),
+        types::Action::Members => list_members(command),
+        types::Action::AddMember | types::Action::RemoveMember | types::Action::SetMember => {
+            handle_member_admin(command)
+        }
+    }
+}
+
+fn stop_server() -> error::Result<types::Response> {
+    let report = state::drain_server()?;
+    let message = if report.clean {
+        "Server stopped after all requests finished".to_string()
+    } else {
+        format!(
+            "Server stopped, {} request(s) did not finish in time",
+            report.unfinished
+        )
+    };
+    types::Response::with_payload(message, &report)
+}
+
+fn reload_server() -> error::Result<types::Response> {
+    state::reload_server()?;
+    Ok(types::Response::ok("Configuration reloaded".to_string()))
+}
+
+fn signal_alive(credentials: &access::Credentials) -> error::Result<types::Response> {
+    let state = state::get_server_state()?;
+    let admin = access::roles(&state.config, credentials).admin;
+    let mut database = types::DatabaseHealth {
+        backend: storage::Backend::from_config(&state.config)?
+            .name()
+            .to_string(),
+        healthy: false,
+        schema_version: None,
+        error: None,
+    };
+    let mut pending_confirmations = None;
+    let checked = state::get_storage().and_then(|storage| {
+        database.schema_version = Some(storage.schema_version()?);
+        pending_confirmations =
+            Some(storage.pending_subscriptions(state.config.subscription_expiry_hours)?);
+        Ok(())
+    });
+    match checked {
+        Ok(()) => database.healthy = true,
+        Err(err) if admin => database.error = Some(err.to_string()),
+        Err(_) => {}
+    }
+    let status = types::ServerStatus {
+        server_version: state.server_version.clone(),
+        pid: state.pid,
+        start_time: state.start_time,
+        uptime_seconds: (chrono::Utc::now() - state.start_time).num_seconds(),
+        requests: stats::requests(),
+        workers: stats::workers(&state.config.workers),
+        database,
+        outbound_deliveries: stats::outbound_deliveries(),
+        pending_confirmations,
+        config: if admin {
+            Some(state.config.redacted())
+        } else {
+            None
+        },
+    };
+    types::Response::with_payload("Server alive".to_string(), &status)
+}
+
+fn handle_subscription_request(
+    command: types::Command,
+    action: types::SubscriptionAction,
+) -> error::Result<types::Response> {
+    let request_type = match action {
+        types::SubscriptionAction::Subscribe => "SUBSCRIBE",
+        types::SubscriptionAction::Unsubscribe => "UNSUBSCRIBE",
+    };
     let data = command
         .data
-        .ok_or(error::Error::SubscriptionRequestWithoutData)?;
+        .ok_or(error::Error::SubscriptionRequestWithoutData { request_type })?;
+    let list_name = command
+        .list_name
+        .ok_or(error::Error::RequestWithoutListName {
+            request_type,
+            request: data.clone(),
+        })?;
     let mail = mailparse::parse_mail(data.as_bytes()).context(error::MailParseError {})?;
-    let from = mail
-        .headers
-        .get_first_value("From")
+    let mailboxes = from_mailboxes(&mail.headers, &data)?;
+    let list = database::get_list(&list_name)?;
+    let mut addresses = vec![];
+    let mut rejected = None;
+    for mailbox in mailboxes {
+        match list.address(&mailbox.address) {
+            Ok(address) => addresses.push(address),
+            Err(err) => {
+                log::warn!("Ignoring address in {} request: {}", request_type, err);
+                rejected.get_or_insert(err);
+            }
+        }
+    }
+    if addresses.is_empty() {
+        return Err(rejected.unwrap_or(error::Error::CouldNotParseHeader {
+            header: "FROM",
+            request: data.clone(),
+        }));
+    }
+    let subscriptions = addresses
+        .into_iter()
+        .map(|address| types::Subscription {
+            email: address,
+            uuid: uuid::Uuid::new_v4(),
+            action,
+        })
+        .collect();
+    let results = database::insert_subscriptions(
+        &list_name,
+        subscriptions,
+        &data,
+        send_mail_for_subscription,
+    )?;
+    let outcomes: std::vec::Vec<String> = results
+        .iter()
+        .map(|result| format!("{} {}", result.email, result.outcome.describe()))
+        .collect();
+    for outcome in outcomes.iter() {
+        log::info!("{} request for {}: {}", request_type, list_name, outcome);
+    }
+    types::Response::with_payload(
+        format!(
+            "{} request for {}: {}",
+            action.as_str(),
+            list_name,
+            outcomes.join(", ")
+        ),
+        &results,
+    )
+}
+
+/// The mailboxes of the From header. The raw header is parsed, so that
+/// encoded words in display names cannot smuggle in addresses.
+fn from_mailboxes(
+    headers: &[mailparse::MailHeader],
+    request: &str,
+) -> error::Result<std::vec::Vec<parse_mail::Mailbox>> {
+    use mailparse::MailHeaderMap;
+    let from = headers
+        .get_first_header("From")
         .ok_or(error::Error::EmptyOrMissingHeader {
             header: "FROM",
+            request: request.to_string(),
+        })?;
+    parse_mail::parse_address_list(&String::from_utf8_lossy(from.get_value_raw()))
+}
+
+fn handle_confirm(command: types::Command) -> error::Result<types::Response> {
+    let data = command
+        .data
+        .ok_or(error::Error::ConfirmationRequestWithoutData)?;
+    let token = match uuid::Uuid::parse_str(data.trim()) {
+        Ok(token) => token,
+        Err(_) => get_token_from_reply(&data)?,
+    };
+    let (list, subscription) = database::confirm_subscription(&token)?;
+    log::info!(
+        "Confirmed {} of {} with token {}",
+        subscription.action.as_str(),
+        subscription.email,
+        token
+    );
+    let notice = match subscription.action {
+        types::SubscriptionAction::Subscribe => template::Template::Welcome,
+        types::SubscriptionAction::Unsubscribe => template::Template::Goodbye,
+    };
+    // the confirmation is stored, a lost notice must not report it as failed
+    if let Err(err) = send_notice(&list, notice, &subscription.email) {
+        log::warn!(
+            "Could not send {} notice to {}: {}",
+            notice.name(),
+            subscription.email,
+            err
+        );
+    }
+    types::Response::with_payload(
+        format!(
+            "Confirmed {} of {} for {}",
+            subscription.action.as_str(),
+            subscription.email,
+            list.email
+        ),
+        &types::ConfirmationResult {
+            list_name: list.email.clone(),
+            email: subscription.email.delivery(),
+            action: subscription.action.as_str().to_string(),
+        },
+    )
+}
+
+fn send_notice(
+    list: &types::MailingList,
+    notice: template::Template,
+    email: &address::Address,
+) -> error::Result<()> {
+    let config = state::get_server_state()?.config;
+    let mail = mail::notice(&config, list, notice, email)?;
+    mail::deliver(&config.mail_transport, &mail)
+}
+
+fn handle_post(command: types::Command) -> error::Result<types::Response> {
+    let data = command.data.ok_or(error::Error::PostRequestWithoutData)?;
+    let list_name = command
+        .list_name
+        .ok_or(error::Error::RequestWithoutListName {
+            request_type: "POST",
             request: data.clone(),
         })?;
-    let addresses = parse_mail::get_addresses_in_from_header(&from);
-    if addresses.is_empty() {
-        return Err(error::Error::CouldNotParseHeader {
+    let (headers, _) =
+        mailparse::parse_headers(data.as_bytes()).context(error::MailParseError {})?;
+    let mailbox = from_mailboxes(&headers, &data)?.into_iter().next().ok_or(
+        error::Error::CouldNotParseHeader {
             header: "FROM",
             request: data.clone(),
-        });
+        },
+    )?;
+    let sender = database::get_list(&list_name)?.address(&mailbox.address)?;
+    let (list, recipients) = match database::get_post_recipients(&list_name, &sender) {
+        Err(err @ error::Error::PostSenderNotMember { .. }) => {
+            if state::get_server_state()?.config.send_rejections {
+                send_rejection(&list_name, &sender.delivery(), &headers, &err)?;
+            }
+            return Err(err);
+        }
+        result => result?,
+    };
+    let message = mail::rewrite_post(&list, &data)?;
+    let transport = state::get_server_state()?.config.mail_transport;
+    let mut failures = 0;
+    for recipient in recipients.iter() {
+        let mail = mail::Mail {
+            envelope_from: list.bounce_address(),
+            recipients: vec![recipient.clone()],
+            message: message.clone(),
+        };
+        if let Err(err) = mail::deliver(&transport, &mail) {
+            log::warn!(
+                "Could not deliver post from {} on {} to {}: {}",
+                sender,
+                list.email,
+                recipient,
+                err
+            );
+            failures += 1;
+        }
     }
+    log::info!(
+        "Distributed post from {} on {} to {} of {} members",
+        sender,
+        list.email,
+        recipients.len() - failures,
+        recipients.len()
+    );
+    types::Response::with_payload(
+        format!(
+            "Distributed post to {} of {} members",
+            recipients.len() - failures,
+            recipients.len()
+        ),
+        &types::PostResult {
+            list_name: list.email.clone(),
+            recipients: recipients.len(),
+            failures,
+        },
+    )
+}
+
+fn send_rejection(
+    list_name: &str,
+    sender: &str,
+    headers: &[mailparse::MailHeader],
+    reason: &error::Error,
+) -> error::Result<()> {
+    use mailparse::MailHeaderMap;
+    let list = database::get_list(list_name)?;
+    let config = state::get_server_state()?.config;
+    let data = serde_json::json!({
+        "post": { "subject": headers.get_first_value("Subject").unwrap_or_default() },
+        "reason": reason.to_string(),
+    });
+    let mail = mail::system_mail(&config, &list, template::Template::Rejection, sender, data)?;
+    mail::deliver(&config.mail_transport, &mail)
+}
+
+fn render_template(command: types::Command) -> error::Result<types::Response> {
     let list_name = command
         .list_name
         .ok_or(error::Error::RequestWithoutListName {
-            request_type: "SUBSCRIBE",
-            request: data.clone(),
+            request_type: "RENDER TEMPLATE",
+            request: command.data.clone().unwrap_or_default(),
         })?;
-    let subscriptions = addresses
-        .into_iter()
-        .map(|address| types::Subscription {
-            email: address,
-            uuid: uuid::Uuid::new_v4().to_string(),
+    let name = command
+        .data
+        .ok_or(error::Error::RenderTemplateRequestWithoutName)?;
+    let template = template::Template::parse(&name)?;
+    let list = database::get_list(&list_name)?;
+    let config = state::get_server_state()?.config;
+    let rendered = template::render(&config, &list, template, template::sample_data(template))?;
+    let text = format!("Subject: {}\n\n{}", rendered.subject, rendered.body);
+    types::Response::with_payload(format!("Rendered template {}", name), &text)
+}
+
+fn handle_list_admin(command: types::Command) -> error::Result<types::Response> {
+    let request_type = match command.action {
+        types::Action::CreateList => "CREATE LIST",
+        types::Action::DeleteList => "DELETE LIST",
+        types::Action::EnableList => "ENABLE LIST",
+        types::Action::DisableList => "DISABLE LIST",
+        _ => "SET LIST",
+    };
+    let list_name = command
+        .list_name
+        .ok_or(error::Error::RequestWithoutListName {
+            request_type,
+            request: command.data.clone().unwrap_or_default(),
+        })?;
+    match command.action {
+        types::Action::CreateList => {
+            address::list_address(&list_name)?;
+            database::create_list(&list_name, &parse_list_settings(command.data)?)?
+        }
+        types::Action::DeleteList => database::delete_list(&list_name)?,
+        types::Action::EnableList => database::set_list_enabled(&list_name, true)?,
+        types::Action::DisableList => database::set_list_enabled(&list_name, false)?,
+        _ => database::update_list(&list_name, &parse_list_settings(command.data)?)?,
+    }
+    log::info!(
+        "{} {} requested by {}",
+        request_type,
+        list_name,
+        command.originator
+    );
+    Ok(types::Response::ok(format!(
+        "{} {} done",
+        request_type, list_name
+    )))
+}
+
+fn list_members(command: types::Command) -> error::Result<types::Response> {
+    let list_name = command
+        .list_name
+        .ok_or(error::Error::RequestWithoutListName {
+            request_type: "MEMBERS",
+            request: command.data.clone().unwrap_or_default(),
+        })?;
+    let data = command.data.ok_or(error::Error::MemberRequestWithoutData {
+        request_type: "MEMBERS",
+    })?;
+    let query: types::MemberQuery =
+        serde_json::from_str(&data).context(error::RequestParseError {})?;
+    let page = database::list_members(&list_name, &query)?;
+    types::Response::with_payload(
+        format!(
+            "{} of {} members of {}",
+            page.members.len(),
+            page.total,
+            list_name
+        ),
+        &page,
+    )
+}
+
+fn handle_member_admin(command: types::Command) -> error::Result<types::Response> {
+    let request_type = match command.action {
+        types::Action::AddMember => "ADD MEMBER",
+        types::Action::RemoveMember => "REMOVE MEMBER",
+        _ => "SET MEMBER",
+    };
+    let list_name = command
+        .list_name
+        .ok_or(error::Error::RequestWithoutListName {
+            request_type,
+            request: command.data.clone().unwrap_or_default(),
+        })?;
+    let data = command
+        .data
+        .ok_or(error::Error::MemberRequestWithoutData { request_type })?;
+    let change: types::MemberChange =
+        serde_json::from_str(&data).context(error::RequestParseError {})?;
+    let email = database::get_list(&list_name)?.address(&change.email)?;
+    match command.action {
+        types::Action::AddMember => {
+            let enabled = change.enabled.unwrap_or(true);
+            let list = database::add_member(&list_name, &email, enabled)?;
+            if change.welcome {
+                send_notice(&list, template::Template::Welcome, &email)?;
+            }
+        }
+        types::Action::RemoveMember => database::remove_member(&list_name, &email)?,
+        _ => {
+            let enabled = change.enabled.unwrap_or(true);
+            database::set_member_enabled(&list_name, &email, enabled)?
+        }
+    }
+    log::info!(
+        "{} {} on {} requested by {}",
+        request_type,
+        change.email,
+        list_name,
+        command.originator
+    );
+    Ok(types::Response::ok(format!(
+        "{} {} on {} done",
+        request_type, change.email, list_name
+    )))
+}
+
+fn parse_list_settings(data: Option<String>) -> error::Result<types::ListSettings> {
+    let settings: types::ListSettings = match data {
+        Some(data) => serde_json::from_str(&data).context(error::RequestParseError {})?,
+        None => types::ListSettings::default(),
+    };
+    settings.validate()?;
+    Ok(settings)
+}
+
+fn get_token_from_reply(data: &str) -> error::Result<uuid::Uuid> {
+    use mailparse::MailHeaderMap;
+    lazy_static::lazy_static! {
+        static ref REGEX : regex::Regex = regex::Regex::new(
+            "[[:xdigit:]]{8}-[[:xdigit:]]{4}-[[:xdigit:]]{4}-[[:xdigit:]]{4}-[[:xdigit:]]{12}").unwrap();
+    }
+    let mail = mailparse::parse_mail(data.as_bytes()).context(error::MailParseError {})?;
+    let subject = mail.headers.get_first_value("Subject").unwrap_or_default();
+    REGEX
+        .find(&subject)
+        .and_then(|token| uuid::Uuid::parse_str(token.as_str()).ok())
+        .ok_or(error::Error::ConfirmationRequestWithoutToken {
+            request: data.to_string(),
         })
-        .collect();
-    database::insert_subscriptions(&list_name, subscriptions, &data, send_mail_for_subscription)?;
-    Ok(())
 }
 
-fn send_mail_for_subscription(_subscription: &types::Subscription) -> error::Result<()> {
-    Ok(())
+/// Sends the confirmation of new and renewed requests, members asking to
+/// subscribe again get a notice instead.
+fn send_mail_for_subscription(
+    list: &types::MailingList,
+    subscription: &types::Subscription,
+    outcome: types::SubscriptionOutcome,
+) -> error::Result<()> {
+    let config = state::get_server_state()?.config;
+    let mail = match outcome {
+        types::SubscriptionOutcome::Requested | types::SubscriptionOutcome::Renewed => {
+            mail::confirmation(&config, list, subscription)?
+        }
+        types::SubscriptionOutcome::AlreadySubscribed => mail::notice(
+            &config,
+            list,
+            template::Template::AlreadySubscribed,
+            &subscription.email,
+        )?,
+        types::SubscriptionOutcome::Pending | types::SubscriptionOutcome::NotSubscribed => {
+            return Ok(())
+        }
+    };
+    mail::deliver(&config.mail_transport, &mail)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{error, protocol};
+
+    #[test]
+    fn busy_server_rejects_request() {
+        let (mut client, server) = std::os::unix::net::UnixStream::pair().unwrap();
+        // the client is not read from, the answer waits in the buffer
+        super::reject_busy(server);
+        let err = protocol::client_handshake(&mut client).err().unwrap();
+        assert_eq!(err.kind(), error::ErrorKind::Busy);
+    }
+
+    #[test]
+    fn token_from_reply() {
+        let reply = "From: frank@example.org\r\n\
+                     Subject: Re: confirm 4B0D4A45-8C3E-4A0E-A2B0-2F3B1E6C3A55\r\n\
+                     \r\n\
+                     yes, please\r\n";
+        assert_eq!(
+            super::get_token_from_reply(reply).unwrap().to_string(),
+            "4b0d4a45-8c3e-4a0e-a2b0-2f3b1e6c3a55"
+        );
+        assert!(super::get_token_from_reply("Subject: Re: hello\r\n\r\n").is_err());
+    }
 }
@@ -0,0 +1,178 @@
+use crate::types;
+use std::os::unix::net::{SocketAddr, UnixListener, UnixStream};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+lazy_static::lazy_static! {
+    static ref COORDINATOR: Coordinator = Coordinator::new();
+    static ref LISTENER: Mutex<Option<SocketAddr>> = Mutex::new(None);
+}
+
+/// Counts the work shutdown has to wait for: queued connections and
+/// running requests.
+pub struct Coordinator {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+struct State {
+    stopping: bool,
+    in_flight: usize,
+}
+
+/// Keeps the coordinator from finishing the drain while alive.
+pub struct InFlight<'a> {
+    coordinator: &'a Coordinator,
+}
+
+impl Coordinator {
+    pub fn new() -> Coordinator {
+        Coordinator {
+            state: Mutex::new(State {
+                stopping: false,
+                in_flight: 0,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    pub fn track(&self) -> InFlight<'_> {
+        self.lock().in_flight += 1;
+        InFlight { coordinator: self }
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.lock().stopping
+    }
+
+    /// Marks the coordinator as stopping, false if it was already.
+    pub fn begin(&self) -> bool {
+        let mut state = self.lock();
+        !std::mem::replace(&mut state.stopping, true)
+    }
+
+    /// Waits up to `deadline` until nothing is in flight.
+    pub fn drain(&self, deadline: Duration) -> types::ShutdownReport {
+        let start = Instant::now();
+        let mut state = self.lock();
+        while state.in_flight > 0 {
+            let remaining = match deadline.checked_sub(start.elapsed()) {
+                Some(remaining) if !remaining.is_zero() => remaining,
+                _ => break,
+            };
+            state = self
+                .changed
+                .wait_timeout(state, remaining)
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .0;
+        }
+        types::ShutdownReport {
+            clean: state.in_flight == 0,
+            unfinished: state.in_flight,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Default for Coordinator {
+    fn default() -> Coordinator {
+        Coordinator::new()
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.coordinator.lock().in_flight -= 1;
+        self.coordinator.changed.notify_all();
+    }
+}
+
+/// Tracks work in the coordinator of the daemon.
+pub fn track() -> InFlight<'static> {
+    COORDINATOR.track()
+}
+
+pub fn is_stopping() -> bool {
+    COORDINATOR.is_stopping()
+}
+
+/// Remembers the address of the listener the daemon accepts on, which is
+/// not the configured socket under socket activation.
+pub fn register_listener(listener: &UnixListener) {
+    match listener.local_addr() {
+        Ok(address) => {
+            *LISTENER
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(address)
+        }
+        Err(err) => log::warn!("Shutdown cannot wake the listener: {}", err),
+    }
+}
+
+/// Stops accepting connections and waits for the work in flight, at most
+/// `deadline`.
+pub fn drain(deadline: Duration) -> types::ShutdownReport {
+    if COORDINATOR.begin() {
+        let listener = LISTENER
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone();
+        if let Some(address) = listener {
+            wake(&address);
+        }
+    }
+    COORDINATOR.drain(deadline)
+}
+
+/// The accepting thread checks for shutdown after every connection.
+fn wake(address: &SocketAddr) {
+    let _ = UnixStream::connect_addr(address);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    #[test]
+    fn drain_waits_for_work_in_flight_until_deadline() {
+        let coordinator = super::Coordinator::new();
+        assert!(coordinator.begin());
+        assert!(!coordinator.begin());
+        assert!(coordinator.is_stopping());
+
+        std::thread::scope(|scope| {
+            let request = coordinator.track();
+            scope.spawn(move || {
+                std::thread::sleep(Duration::from_millis(50));
+                drop(request);
+            });
+            let report = coordinator.drain(Duration::from_secs(10));
+            assert!(report.clean);
+        });
+
+        let _stuck = coordinator.track();
+        let report = coordinator.drain(Duration::from_millis(10));
+        assert!(!report.clean);
+        assert_eq!(report.unfinished, 1);
+    }
+
+    #[test]
+    fn wakes_listener_by_its_own_address() {
+        use std::os::linux::net::SocketAddrExt;
+        let address = std::os::unix::net::SocketAddr::from_abstract_name(format!(
+            "simplemm-test-{}",
+            std::process::id()
+        ))
+        .unwrap();
+        // like a socket passed by systemd, not at the configured path
+        let listener = std::os::unix::net::UnixListener::bind_addr(&address).unwrap();
+        let accepting = std::thread::spawn(move || listener.accept().is_ok());
+        super::wake(&address);
+        assert!(accepting.join().unwrap());
+    }
+}
@@ -1,16 +1,35 @@
-use crate::{error, file, types};
+use crate::storage::Storage;
+use crate::{config, error, file, shutdown, systemd, types};
 use snafu::ResultExt;
+use std::sync::Arc;
 
 lazy_static::lazy_static! {
     static ref STATE: std::sync::RwLock<Option<types::DaemonState>> = std::sync::RwLock::new(None);
+    static ref STORAGE: std::sync::RwLock<Option<Arc<dyn Storage>>> = std::sync::RwLock::new(None);
+    static ref EXITING: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    static ref CONFIG_FILE: std::sync::Mutex<String> = std::sync::Mutex::new(String::new());
 }
 
 pub fn get_server_version() -> &'static str {
     env!("CARGO_PKG_VERSION")
 }
 
-pub fn start_server(config: &types::Config) -> error::Result<()> {
-    log_start(&config);
+/// Starts the daemon with `config` read from `config_file` and its storage.
+/// `reload_server` replaces the configuration, the storage stays.
+pub fn start_server(
+    config_file: &str,
+    config: &types::Config,
+    storage: Box<dyn Storage>,
+) -> error::Result<()> {
+    log_start(config);
+    *CONFIG_FILE
+        .lock()
+        .map_err(|err| Box::new(err) as Box<dyn std::error::Error>)
+        .context(error::ServerStateError {})? = config_file.to_string();
+    *STORAGE
+        .write()
+        .map_err(|err| Box::new(err) as Box<dyn std::error::Error>)
+        .context(error::ServerStateError {})? = Some(Arc::from(storage));
     let now = chrono::Utc::now();
     let mut state = STATE
         .write()
@@ -18,21 +37,113 @@ pub fn start_server(config: &types::Config) -> error::Result<()> {
         .context(error::ServerStateError {})?;
     *state = Some(types::DaemonState::new(config, &now, get_server_version()));
     set_exit_handler()?;
+    set_reload_handler()?;
+    notify_ready(config);
     Ok(())
 }
 
-pub fn stop_server() {
-    let state = STATE.write();
-    if let Ok(mut state) = state {
-        if let Some(ref state) = *state {
-            let config = state.config.clone();
+/// Reads the configuration file again and swaps it in. Keeps the running
+/// configuration if the new one is invalid.
+pub fn reload_server() -> error::Result<()> {
+    // one reload at a time
+    let config_file = CONFIG_FILE
+        .lock()
+        .map_err(|err| Box::new(err) as Box<dyn std::error::Error>)
+        .context(error::ServerStateError {})?;
+    systemd::notify("RELOADING=1");
+    let result = swap_config(&config_file);
+    let config = get_server_state()?.config;
+    match &result {
+        Ok(()) => log::info!("Reloaded configuration from {}", *config_file),
+        Err(err) => log::error!(
+            "Keeping running configuration, {} is invalid: {}",
+            *config_file,
+            err
+        ),
+    }
+    notify_ready(&config);
+    result
+}
+
+fn swap_config(config_file: &str) -> error::Result<()> {
+    let current = get_server_state()?.config;
+    let mut config = config::read_config(config_file)?;
+    config::validate(&config)?;
+    keep_restart_settings(&current, &mut config);
+    let mut state = STATE
+        .write()
+        .map_err(|err| Box::new(err) as Box<dyn std::error::Error>)
+        .context(error::ServerStateError {})?;
+    if let Some(state) = state.as_mut() {
+        state.config = config;
+    }
+    Ok(())
+}
+
+/// Settings the running process cannot change keep their value until the
+/// next restart. The database is opened before dropping privileges, so that
+/// `db_password_file` may be readable by root only.
+fn keep_restart_settings(current: &types::Config, config: &mut types::Config) {
+    fn keep<T: PartialEq + Clone>(name: &str, current: &T, new: &mut T) {
+        if current != new {
+            log::warn!("Changed setting {} takes effect after a restart", name);
+            *new = current.clone();
+        }
+    }
+    keep("uid", &current.uid, &mut config.uid);
+    keep("gid", &current.gid, &mut config.gid);
+    keep("pid_file", &current.pid_file, &mut config.pid_file);
+    keep("working_dir", &current.working_dir, &mut config.working_dir);
+    keep("socket", &current.socket, &mut config.socket);
+    keep("workers", &current.workers, &mut config.workers);
+    keep("db_url", &current.db_url, &mut config.db_url);
+    keep(
+        "db_password_file",
+        &current.db_password_file,
+        &mut config.db_password_file,
+    );
+    keep("db_pool", &current.db_pool, &mut config.db_pool);
+    // may be overridden on the command line
+    config.logging = current.logging.clone();
+}
+
+/// Stops accepting connections and waits for the requests in flight, at
+/// most `shutdown_timeout_seconds`.
+pub fn drain_server() -> error::Result<types::ShutdownReport> {
+    let config = get_server_state()?.config;
+    let deadline = std::time::Duration::from_secs(config.shutdown_timeout_seconds);
+    log::info!("simplemmd stopping, waiting for requests in flight");
+    systemd::notify("STOPPING=1\nSTATUS=Waiting for requests in flight");
+    let report = shutdown::drain(deadline);
+    if !report.clean {
+        log::warn!(
+            "{} request(s) did not finish within {} seconds",
+            report.unfinished,
+            config.shutdown_timeout_seconds
+        );
+    }
+    Ok(report)
+}
+
+/// Removes socket and pid file and exits, after `drain_server`.
+pub fn exit_server() -> ! {
+    // a signal and a stop request may race, only one of them exits
+    let _exiting = EXITING.lock();
+    if let Ok(mut storage) = STORAGE.write() {
+        *storage = None;
+    }
+    // workers that outlived the drain deadline still read the state
+    if let Ok(config) = get_server_state().map(|state| state.config) {
+        if !systemd::socket_activated() {
             file::delete_file(&config.socket);
-            file::delete_file(&config.pid_file);
-            log_end(&config);
         }
-        *state = None;
+        if let Some(pid_file) = &config.pid_file {
+            file::delete_file(pid_file);
+        }
+        log_end(&config);
     }
-    std::process::exit(-1);
+    log::logger().flush();
+    std::process::exit(0);
 }
 
 pub fn get_server_state() -> error::Result<types::DaemonState> {
@@ -40,15 +151,53 @@ pub fn get_server_state() -> error::Result<types::DaemonState> {
         .read()
         .map_err(|err| Box::new(err) as Box<dyn std::error::Error>)
         .context(error::ServerStateError {})?;
-    let state = (*lock_state).as_ref().unwrap();
+    let state = (*lock_state)
+        .as_ref()
+        .ok_or_else(|| Box::from("the server is not running") as Box<dyn std::error::Error>)
+        .context(error::ServerStateError {})?;
     Ok(state.clone())
 }
 
+pub fn get_storage() -> error::Result<Arc<dyn Storage>> {
+    let storage = STORAGE
+        .read()
+        .map_err(|err| Box::new(err) as Box<dyn std::error::Error>)
+        .context(error::ServerStateError {})?;
+    storage.clone().ok_or(error::Error::DbNotOpened)
+}
+
 fn set_exit_handler() -> error::Result<()> {
-    ctrlc::set_handler(stop_server).context(error::ExitHandlerError {})?;
+    ctrlc::set_handler(|| {
+        let _ = drain_server();
+        exit_server();
+    })
+    .context(error::ExitHandlerError {})?;
     Ok(())
 }
 
+/// Reloads on SIGHUP, on a thread of its own since reloading takes locks.
+fn set_reload_handler() -> error::Result<()> {
+    let mut signals = signal_hook::iterator::Signals::new([signal_hook::consts::SIGHUP])
+        .context(error::SignalHandlerError {})?;
+    std::thread::Builder::new()
+        .name("reload".to_string())
+        .spawn(move || {
+            for _ in signals.forever() {
+                let _ = reload_server();
+            }
+        })
+        .context(error::SignalHandlerError {})?;
+    Ok(())
+}
+
+fn notify_ready(config: &types::Config) {
+    systemd::notify(&format!(
+        "READY=1\nSTATUS=Serving requests on {}\nMAINPID={}",
+        config.socket,
+        std::process::id()
+    ));
+}
+
 fn log_start(config: &types::Config) {
     log::info!(
         "simplemmd started, uid = {}, gid = {}",
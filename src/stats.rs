@@ -0,0 +1,77 @@
+use crate::types;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+static REQUESTS: AtomicU64 = AtomicU64::new(0);
+static FAILED: AtomicU64 = AtomicU64::new(0);
+static REFUSED: AtomicU64 = AtomicU64::new(0);
+static REJECTED_BUSY: AtomicU64 = AtomicU64::new(0);
+static WORKERS_BUSY: AtomicUsize = AtomicUsize::new(0);
+static QUEUED: AtomicUsize = AtomicUsize::new(0);
+static DELIVERING: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts something that is going on while alive.
+pub struct Gauge(&'static AtomicUsize);
+
+impl Gauge {
+    fn start(gauge: &'static AtomicUsize) -> Gauge {
+        gauge.fetch_add(1, Ordering::SeqCst);
+        Gauge(gauge)
+    }
+}
+
+impl Drop for Gauge {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Counts an answered request, errors also as failed.
+pub fn count_request(response: &types::Response) {
+    REQUESTS.fetch_add(1, Ordering::SeqCst);
+    if response.status == types::Status::Error {
+        FAILED.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+pub fn count_refused() {
+    REFUSED.fetch_add(1, Ordering::SeqCst);
+}
+
+pub fn count_rejected_busy() {
+    REJECTED_BUSY.fetch_add(1, Ordering::SeqCst);
+}
+
+pub fn worker_busy() -> Gauge {
+    Gauge::start(&WORKERS_BUSY)
+}
+
+pub fn queued() -> Gauge {
+    Gauge::start(&QUEUED)
+}
+
+pub fn delivering() -> Gauge {
+    Gauge::start(&DELIVERING)
+}
+
+pub fn requests() -> types::RequestCounters {
+    types::RequestCounters {
+        total: REQUESTS.load(Ordering::SeqCst),
+        failed: FAILED.load(Ordering::SeqCst),
+        refused: REFUSED.load(Ordering::SeqCst),
+        rejected_busy: REJECTED_BUSY.load(Ordering::SeqCst),
+    }
+}
+
+pub fn workers(config: &types::WorkerConfig) -> types::WorkerUsage {
+    types::WorkerUsage {
+        threads: config.threads,
+        busy: WORKERS_BUSY.load(Ordering::SeqCst),
+        queued: QUEUED.load(Ordering::SeqCst),
+        queue_size: config.queue_size,
+    }
+}
+
+/// Mails being handed to the transport right now.
+pub fn outbound_deliveries() -> usize {
+    DELIVERING.load(Ordering::SeqCst)
+}
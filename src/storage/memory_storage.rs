@@ -0,0 +1,386 @@
+use super::{migrations, subscription_outcome, Storage, StoredSubscription};
+use crate::{address, error, types};
+use std::collections::BTreeMap;
+
+/// Keeps everything in the memory of the daemon, for tests and development.
+pub struct MemoryStorage {
+    data: std::sync::Mutex<Data>,
+}
+
+#[derive(Clone, Default)]
+struct Data {
+    next_list_id: i32,
+    lists: BTreeMap<String, types::MailingList>,
+    /// by list id and normalized address, ordered like the SQL backends
+    members: BTreeMap<(i32, String), StoredMember>,
+    subscriptions: BTreeMap<uuid::Uuid, PendingSubscription>,
+}
+
+#[derive(Clone)]
+struct StoredMember {
+    original: String,
+    enabled: bool,
+}
+
+#[derive(Clone)]
+struct PendingSubscription {
+    list_id: i32,
+    email: address::Address,
+    action: types::SubscriptionAction,
+    timestamp: chrono::DateTime<chrono::Utc>,
+    confirmation_sent: chrono::DateTime<chrono::Utc>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage {
+            data: std::sync::Mutex::new(Data {
+                next_list_id: 1,
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Runs `operation` on a copy of the data and keeps the copy on success.
+    fn with_transaction<T, F>(&self, operation: F) -> error::Result<T>
+    where
+        F: FnOnce(&mut Data) -> error::Result<T>,
+    {
+        let mut data = self
+            .data
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut copy = data.clone();
+        let result = operation(&mut copy)?;
+        *data = copy;
+        Ok(result)
+    }
+}
+
+impl Default for MemoryStorage {
+    fn default() -> MemoryStorage {
+        MemoryStorage::new()
+    }
+}
+
+impl Data {
+    fn list(&self, list_name: &str) -> error::Result<&types::MailingList> {
+        self.lists
+            .get(list_name)
+            .ok_or(error::Error::DbMailingListDoesNotExist {
+                list_name: list_name.to_string(),
+            })
+    }
+
+    fn list_by_id(&self, list_id: i32) -> Option<&types::MailingList> {
+        self.lists.values().find(|list| list.id == list_id)
+    }
+
+    fn members_of(&self, list_id: i32) -> impl Iterator<Item = (&String, &StoredMember)> {
+        self.members
+            .range((list_id, String::new())..(list_id + 1, String::new()))
+            .map(|((_, email), member)| (email, member))
+    }
+
+    fn insert_member(&mut self, list_id: i32, email: &address::Address, enabled: bool) {
+        self.members.insert(
+            (list_id, email.normalized.clone()),
+            StoredMember {
+                original: email.original.clone(),
+                enabled,
+            },
+        );
+    }
+
+    fn subscription(
+        &self,
+        token: &uuid::Uuid,
+        expiry_hours: u32,
+    ) -> error::Result<(types::MailingList, types::Subscription)> {
+        let not_found = || error::Error::SubscriptionDoesNotExist {
+            token: token.to_string(),
+        };
+        let pending = self.subscriptions.get(token).ok_or_else(not_found)?;
+        let expiry = chrono::Duration::hours(i64::from(expiry_hours));
+        if pending.timestamp < chrono::Utc::now() - expiry {
+            return Err(error::Error::SubscriptionExpired {
+                token: token.to_string(),
+            });
+        }
+        let list = self.list_by_id(pending.list_id).ok_or_else(not_found)?;
+        Ok((
+            list.clone(),
+            types::Subscription {
+                email: pending.email.clone(),
+                uuid: *token,
+                action: pending.action,
+            },
+        ))
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn schema_version(&self) -> error::Result<u32> {
+        Ok(migrations::SCHEMA_VERSION)
+    }
+
+    fn migrate(&self) -> error::Result<std::vec::Vec<u32>> {
+        Ok(std::vec::Vec::new())
+    }
+
+    fn insert_subscriptions(
+        &self,
+        list_name: &str,
+        subscriptions: std::vec::Vec<types::Subscription>,
+        _request: &str,
+        cooldown_minutes: u32,
+        expiry_hours: u32,
+    ) -> error::Result<(types::MailingList, std::vec::Vec<StoredSubscription>)> {
+        self.with_transaction(|data| {
+            let list = data.list(list_name)?.clone();
+            list.check_enabled()?;
+            let now = chrono::Utc::now();
+            let expired = now - chrono::Duration::hours(i64::from(expiry_hours));
+            data.subscriptions
+                .retain(|_, pending| pending.timestamp >= expired);
+            let cooldown = chrono::Duration::minutes(i64::from(cooldown_minutes));
+            let mut stored = std::vec::Vec::new();
+            for mut subscription in subscriptions.into_iter() {
+                let member = data
+                    .members
+                    .contains_key(&(list.id, subscription.email.normalized.clone()));
+                let pending = data
+                    .subscriptions
+                    .iter()
+                    .find(|(_, pending)| {
+                        pending.list_id == list.id
+                            && pending.email.normalized == subscription.email.normalized
+                    })
+                    .map(|(uuid, pending)| (*uuid, pending.clone()));
+                let outcome = subscription_outcome(
+                    subscription.action,
+                    member,
+                    pending.as_ref().map(|(_, pending)| {
+                        (pending.action, pending.confirmation_sent > now - cooldown)
+                    }),
+                );
+                match outcome {
+                    types::SubscriptionOutcome::Requested => {
+                        if let Some((uuid, _)) = &pending {
+                            data.subscriptions.remove(uuid);
+                        }
+                        data.subscriptions.insert(
+                            subscription.uuid,
+                            PendingSubscription {
+                                list_id: list.id,
+                                email: subscription.email.clone(),
+                                action: subscription.action,
+                                timestamp: now,
+                                confirmation_sent: now,
+                            },
+                        );
+                    }
+                    types::SubscriptionOutcome::Renewed | types::SubscriptionOutcome::Pending => {
+                        let (uuid, mut renewed) = pending.unwrap();
+                        renewed.email = subscription.email.clone();
+                        renewed.timestamp = now;
+                        if outcome == types::SubscriptionOutcome::Renewed {
+                            renewed.confirmation_sent = now;
+                        }
+                        data.subscriptions.insert(uuid, renewed);
+                        subscription.uuid = uuid;
+                    }
+                    types::SubscriptionOutcome::AlreadySubscribed
+                    | types::SubscriptionOutcome::NotSubscribed => {}
+                }
+                stored.push((subscription, outcome));
+            }
+            Ok((list, stored))
+        })
+    }
+
+    fn discard_subscriptions(&self, tokens: &[uuid::Uuid]) -> error::Result<()> {
+        self.with_transaction(|data| {
+            for token in tokens {
+                data.subscriptions.remove(token);
+            }
+            Ok(())
+        })
+    }
+
+    fn get_list(&self, list_name: &str) -> error::Result<types::MailingList> {
+        self.with_transaction(|data| data.list(list_name).cloned())
+    }
+
+    fn create_list(&self, list_name: &str, settings: &types::ListSettings) -> error::Result<()> {
+        self.with_transaction(|data| {
+            if data.lists.contains_key(list_name) {
+                return Err(error::Error::DbMailingListAlreadyExists {
+                    list_name: list_name.to_string(),
+                });
+            }
+            let mut list = types::MailingList::new(list_name);
+            list.id = data.next_list_id;
+            settings.apply(&mut list);
+            data.next_list_id += 1;
+            data.lists.insert(list_name.to_string(), list);
+            Ok(())
+        })
+    }
+
+    fn update_list(&self, list_name: &str, settings: &types::ListSettings) -> error::Result<()> {
+        self.with_transaction(|data| {
+            let mut list = data.list(list_name)?.clone();
+            settings.apply(&mut list);
+            data.lists.insert(list_name.to_string(), list);
+            Ok(())
+        })
+    }
+
+    fn delete_list(&self, list_name: &str) -> error::Result<()> {
+        self.with_transaction(|data| {
+            let list_id = data.list(list_name)?.id;
+            data.lists.remove(list_name);
+            data.members.retain(|(id, _), _| *id != list_id);
+            data.subscriptions
+                .retain(|_, subscription| subscription.list_id != list_id);
+            Ok(())
+        })
+    }
+
+    fn list_members(
+        &self,
+        list_name: &str,
+        query: &types::MemberQuery,
+    ) -> error::Result<types::MemberPage> {
+        self.with_transaction(|data| {
+            let list_id = data.list(list_name)?.id;
+            let total = data.members_of(list_id).count() as u64;
+            let members = data
+                .members_of(list_id)
+                .skip(query.offset as usize)
+                .take(query.limit as usize)
+                .map(|(_, member)| types::Member {
+                    email: member.original.clone(),
+                    enabled: member.enabled,
+                })
+                .collect();
+            Ok(types::MemberPage {
+                total,
+                offset: query.offset,
+                members,
+            })
+        })
+    }
+
+    fn add_member(
+        &self,
+        list_name: &str,
+        email: &address::Address,
+        enabled: bool,
+    ) -> error::Result<types::MailingList> {
+        self.with_transaction(|data| {
+            let list = data.list(list_name)?.clone();
+            data.insert_member(list.id, email, enabled);
+            Ok(list)
+        })
+    }
+
+    fn update_member(
+        &self,
+        list_name: &str,
+        email: &address::Address,
+        enabled: Option<bool>,
+    ) -> error::Result<()> {
+        self.with_transaction(|data| {
+            let key = (data.list(list_name)?.id, email.normalized.clone());
+            let member = match data.members.get_mut(&key) {
+                Some(member) => member,
+                None => {
+                    return Err(error::Error::DbMemberDoesNotExist {
+                        email: email.original.clone(),
+                        list_name: list_name.to_string(),
+                    })
+                }
+            };
+            match enabled {
+                Some(enabled) => member.enabled = enabled,
+                None => {
+                    data.members.remove(&key);
+                }
+            };
+            Ok(())
+        })
+    }
+
+    fn pending_subscriptions(&self, expiry_hours: u32) -> error::Result<u64> {
+        let data = self
+            .data
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let expired = chrono::Utc::now() - chrono::Duration::hours(i64::from(expiry_hours));
+        Ok(data
+            .subscriptions
+            .values()
+            .filter(|pending| pending.timestamp >= expired)
+            .count() as u64)
+    }
+
+    fn get_subscription(
+        &self,
+        token: &uuid::Uuid,
+        expiry_hours: u32,
+    ) -> error::Result<(types::MailingList, types::Subscription)> {
+        self.with_transaction(|data| data.subscription(token, expiry_hours))
+    }
+
+    fn confirm_subscription(
+        &self,
+        token: &uuid::Uuid,
+        expiry_hours: u32,
+    ) -> error::Result<(types::MailingList, types::Subscription)> {
+        self.with_transaction(|data| {
+            let (list, subscription) = data.subscription(token, expiry_hours)?;
+            data.subscriptions.remove(token);
+            match subscription.action {
+                types::SubscriptionAction::Subscribe => {
+                    data.insert_member(list.id, &subscription.email, true)
+                }
+                types::SubscriptionAction::Unsubscribe => {
+                    data.members
+                        .remove(&(list.id, subscription.email.normalized.clone()));
+                }
+            };
+            Ok((list, subscription))
+        })
+    }
+
+    fn get_post_recipients(
+        &self,
+        list_name: &str,
+        sender: &address::Address,
+    ) -> error::Result<(types::MailingList, std::vec::Vec<String>)> {
+        self.with_transaction(|data| {
+            let list = data.list(list_name)?.clone();
+            list.check_enabled()?;
+            let recipients: std::vec::Vec<(&String, &StoredMember)> = data
+                .members_of(list.id)
+                .filter(|(_, member)| member.enabled)
+                .collect();
+            if !recipients
+                .iter()
+                .any(|(normalized, _)| **normalized == sender.normalized)
+            {
+                return Err(error::Error::PostSenderNotMember {
+                    sender: sender.original.clone(),
+                    list_name: list_name.to_string(),
+                });
+            }
+            let recipients = recipients
+                .into_iter()
+                .map(|(_, member)| address::delivery_address(&member.original))
+                .collect();
+            Ok((list, recipients))
+        })
+    }
+}
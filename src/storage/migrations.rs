@@ -0,0 +1,411 @@
+use crate::{address, error};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The schema version this code works with, the last migration of every backend.
+pub const SCHEMA_VERSION: u32 = 6;
+
+/// Databases that were set up by hand from the former `schema.sql` have
+/// the tables of the first migration but no `schema_version` table.
+pub const UNRECORDED_VERSION: u32 = 1;
+
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub sql: &'static str,
+    pub step: Option<Step>,
+}
+
+/// Work of a migration that SQL cannot do, run after its statements.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Step {
+    /// Normalizes list names and stored addresses the way the code does,
+    /// migration 3 only lowercased them.
+    NormalizeAddresses,
+    /// Drops pending requests whose token was written in its textual form
+    /// and cut to 16 bytes, they can never be confirmed.
+    DropTextualTokens,
+}
+
+/// A list with the rules for the addresses of its members.
+pub struct StoredList {
+    pub id: i32,
+    pub email: String,
+    pub rules: address::LocalPartRules,
+}
+
+/// An address of a member or pending request as stored, `email` is unique
+/// within the list.
+pub struct StoredAddress {
+    pub list_id: i32,
+    pub email: String,
+    pub original: String,
+}
+
+/// Changes that bring stored addresses to their normalized form.
+#[derive(Default, Debug, PartialEq)]
+pub struct Normalization {
+    /// `(list_id, email)` of rows that normalize to the address of a row
+    /// that is kept.
+    pub duplicates: std::vec::Vec<(i32, String)>,
+    /// `(list_id, email, normalized)` in an order that never takes an
+    /// address still in use.
+    pub updates: std::vec::Vec<(i32, String, String)>,
+}
+
+pub static MYSQL: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        sql: include_str!("../../mysql/migrations/0001_initial.sql"),
+        step: None,
+    },
+    Migration {
+        version: 2,
+        name: "list_settings_and_unsubscribe",
+        sql: include_str!("../../mysql/migrations/0002_list_settings_and_unsubscribe.sql"),
+        step: None,
+    },
+    Migration {
+        version: 3,
+        name: "normalized_addresses",
+        sql: include_str!("../../mysql/migrations/0003_normalized_addresses.sql"),
+        step: None,
+    },
+    Migration {
+        version: 4,
+        name: "unique_pending_subscriptions",
+        sql: include_str!("../../mysql/migrations/0004_unique_pending_subscriptions.sql"),
+        step: None,
+    },
+    Migration {
+        version: 5,
+        name: "binary_subscription_tokens",
+        sql: include_str!("../../mysql/migrations/0005_binary_subscription_tokens.sql"),
+        step: Some(Step::DropTextualTokens),
+    },
+    Migration {
+        version: 6,
+        name: "normalized_list_names",
+        sql: include_str!("../../mysql/migrations/0006_normalized_list_names.sql"),
+        step: Some(Step::NormalizeAddresses),
+    },
+];
+
+pub static SQLITE: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        sql: include_str!("../../sqlite/migrations/0001_initial.sql"),
+        step: None,
+    },
+    Migration {
+        version: 2,
+        name: "list_settings_and_unsubscribe",
+        sql: include_str!("../../sqlite/migrations/0002_list_settings_and_unsubscribe.sql"),
+        step: None,
+    },
+    Migration {
+        version: 3,
+        name: "normalized_addresses",
+        sql: include_str!("../../sqlite/migrations/0003_normalized_addresses.sql"),
+        step: None,
+    },
+    Migration {
+        version: 4,
+        name: "unique_pending_subscriptions",
+        sql: include_str!("../../sqlite/migrations/0004_unique_pending_subscriptions.sql"),
+        step: None,
+    },
+    Migration {
+        version: 5,
+        name: "binary_subscription_tokens",
+        sql: include_str!("../../sqlite/migrations/0005_binary_subscription_tokens.sql"),
+        step: None,
+    },
+    Migration {
+        version: 6,
+        name: "normalized_list_names",
+        sql: include_str!("../../sqlite/migrations/0006_normalized_list_names.sql"),
+        step: Some(Step::NormalizeAddresses),
+    },
+];
+
+impl Migration {
+    /// The single statements, migrations have no semicolons inside literals.
+    /// Migrations done by their step have comments only.
+    pub fn statements(&self) -> impl Iterator<Item = &'static str> {
+        self.sql.split(';').map(str::trim).filter(|statement| {
+            statement.lines().any(|line| {
+                let line = line.trim();
+                !line.is_empty() && !line.starts_with("--")
+            })
+        })
+    }
+}
+
+/// The new names of `lists` that are not normalized yet, as pairs of the
+/// old and the new name. Lists differing in case only cannot be merged.
+pub fn list_renames(lists: &[StoredList]) -> error::Result<std::vec::Vec<(String, String)>> {
+    let names = lists
+        .iter()
+        .map(|list| StoredAddress {
+            list_id: 0,
+            email: list.email.clone(),
+            original: list.email.clone(),
+        })
+        .collect();
+    let normalization = normalize(names, |name| Some(address::list_name(&name.original)))?;
+    if let Some((_, name)) = normalization.duplicates.into_iter().next() {
+        return Err(error::Error::DbMigrationConflict {
+            normalized: address::list_name(&name),
+            address: name,
+        });
+    }
+    Ok(normalization
+        .updates
+        .into_iter()
+        .map(|(_, name, normalized)| (name, normalized))
+        .collect())
+}
+
+/// Normalizes the addresses of members or pending requests by the rules of
+/// their list. Addresses that are invalid by now stay as they are.
+pub fn address_normalization(
+    lists: &[StoredList],
+    rows: std::vec::Vec<StoredAddress>,
+) -> error::Result<Normalization> {
+    let rules: BTreeMap<i32, address::LocalPartRules> =
+        lists.iter().map(|list| (list.id, list.rules)).collect();
+    normalize(rows, |row| {
+        let original = if row.original.is_empty() {
+            &row.email
+        } else {
+            &row.original
+        };
+        let rules = rules.get(&row.list_id).copied().unwrap_or_default();
+        address::Address::new(original, rules)
+            .ok()
+            .map(|address| address.normalized)
+    })
+}
+
+/// Of rows that end up with the same address the one already normalized
+/// is kept, or else the first.
+fn normalize(
+    mut rows: std::vec::Vec<StoredAddress>,
+    normalized: impl Fn(&StoredAddress) -> Option<String>,
+) -> error::Result<Normalization> {
+    rows.sort_by(|a, b| (a.list_id, &a.email).cmp(&(b.list_id, &b.email)));
+    let targets: std::vec::Vec<String> = rows
+        .iter()
+        .map(|row| normalized(row).unwrap_or_else(|| row.email.clone()))
+        .collect();
+    let mut kept = BTreeMap::new();
+    for (index, (row, target)) in rows.iter().zip(&targets).enumerate() {
+        let kept_index = kept.entry((row.list_id, target)).or_insert(index);
+        if row.email == *target {
+            *kept_index = index;
+        }
+    }
+    let mut normalization = Normalization::default();
+    let mut taken = BTreeSet::new();
+    let mut pending = std::vec::Vec::new();
+    for (index, (row, target)) in rows.iter().zip(&targets).enumerate() {
+        if kept[&(row.list_id, target)] != index {
+            normalization
+                .duplicates
+                .push((row.list_id, row.email.clone()));
+        } else {
+            taken.insert((row.list_id, row.email.clone()));
+            if row.email != *target {
+                pending.push((row.list_id, row.email.clone(), target.clone()));
+            }
+        }
+    }
+    // an address may only be taken once the row holding it moved on
+    while !pending.is_empty() {
+        let (ready, blocked): (std::vec::Vec<_>, std::vec::Vec<_>) = pending
+            .into_iter()
+            .partition(|(list_id, _, target)| !taken.contains(&(*list_id, target.clone())));
+        if ready.is_empty() {
+            let (_, email, target) = blocked.into_iter().next().unwrap_or_default();
+            return Err(error::Error::DbMigrationConflict {
+                address: email,
+                normalized: target,
+            });
+        }
+        for (list_id, email, target) in ready {
+            taken.remove(&(list_id, email.clone()));
+            taken.insert((list_id, target.clone()));
+            normalization.updates.push((list_id, email, target));
+        }
+        pending = blocked;
+    }
+    Ok(normalization)
+}
+
+/// Whether `token` is the start of a textual UUID, 8, 4 and 2 hex digits
+/// separated by hyphens. A binary token looks like that with a chance of
+/// about 10^-20.
+pub fn is_textual_token(token: &[u8]) -> bool {
+    token.len() == 16
+        && token.iter().enumerate().all(|(index, byte)| match index {
+            8 | 13 => *byte == b'-',
+            _ => byte.is_ascii_hexdigit(),
+        })
+}
+
+/// The migrations to apply on top of `current`.
+pub fn pending(
+    migrations: &'static [Migration],
+    current: u32,
+) -> error::Result<&'static [Migration]> {
+    if current > SCHEMA_VERSION {
+        return Err(error::Error::DbSchemaTooNew {
+            found: current,
+            expected: SCHEMA_VERSION,
+        });
+    }
+    Ok(&migrations[current as usize..])
+}
+
+pub fn check_version(found: u32) -> error::Result<()> {
+    if found > SCHEMA_VERSION {
+        return Err(error::Error::DbSchemaTooNew {
+            found,
+            expected: SCHEMA_VERSION,
+        });
+    }
+    if found < SCHEMA_VERSION {
+        return Err(error::Error::DbSchemaOutdated {
+            found,
+            expected: SCHEMA_VERSION,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{address, error};
+
+    #[test]
+    fn migrations_are_complete_and_ordered() {
+        for migrations in &[super::MYSQL, super::SQLITE] {
+            assert_eq!(migrations.len() as u32, super::SCHEMA_VERSION);
+            for (index, migration) in migrations.iter().enumerate() {
+                assert_eq!(migration.version as usize, index + 1);
+                assert!(migration.statements().count() > 0 || migration.step.is_some());
+            }
+        }
+        // done by the step alone
+        assert_eq!(super::MYSQL[4].statements().count(), 0);
+    }
+
+    fn stored(list_id: i32, email: &str, original: &str) -> super::StoredAddress {
+        super::StoredAddress {
+            list_id,
+            email: email.to_string(),
+            original: original.to_string(),
+        }
+    }
+
+    #[test]
+    fn normalizes_stored_addresses_by_list_rules() {
+        let rules = |fold_case, strip_subaddress| address::LocalPartRules {
+            fold_case,
+            strip_subaddress,
+        };
+        let lists = vec![
+            super::StoredList {
+                id: 1,
+                email: "gemuese@example.com".to_string(),
+                rules: rules(false, false),
+            },
+            super::StoredList {
+                id: 2,
+                email: "obst@example.com".to_string(),
+                rules: rules(true, true),
+            },
+        ];
+        let rows = vec![
+            stored(1, "jane@example.com", "Jane@Example.com"),
+            stored(1, "jürgen@bücher.example", "Jürgen@Bücher.example"),
+            // leaves the address the first row takes
+            stored(1, "Jane@example.com", "Jane+Lists@example.com"),
+            stored(2, "jane+obst@example.com", "Jane+Obst@example.com"),
+            stored(2, "jane@example.com", "jane@example.com"),
+            stored(2, "invalid", "invalid"),
+        ];
+        let normalization = super::address_normalization(&lists, rows).unwrap();
+        assert_eq!(
+            normalization.duplicates,
+            vec![(2, "jane+obst@example.com".to_string())]
+        );
+        assert_eq!(
+            normalization.updates,
+            vec![
+                (
+                    1,
+                    "Jane@example.com".to_string(),
+                    "Jane+Lists@example.com".to_string()
+                ),
+                (
+                    1,
+                    "jürgen@bücher.example".to_string(),
+                    "Jürgen@xn--bcher-kva.example".to_string()
+                ),
+                (
+                    1,
+                    "jane@example.com".to_string(),
+                    "Jane@example.com".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn recognizes_textual_tokens() {
+        let token = uuid::Uuid::parse_str("4b0d4a45-8c3e-4a0e-a2b0-2f3b1e6c3a55").unwrap();
+        assert!(!super::is_textual_token(token.as_bytes()));
+        let textual = token.to_string();
+        assert!(super::is_textual_token(&textual.as_bytes()[..16]));
+        assert!(!super::is_textual_token(textual.as_bytes()));
+        assert!(!super::is_textual_token(b"4b0d4a45-8c3e-4x"));
+    }
+
+    #[test]
+    fn renames_lists_to_their_normalized_name() {
+        let list = |id, email: &str| super::StoredList {
+            id,
+            email: email.to_string(),
+            rules: Default::default(),
+        };
+        let renames = super::list_renames(&[
+            list(1, "Gemuese@Example.com"),
+            list(2, "obst@bücher.example"),
+            list(3, "kaese@example.com"),
+        ])
+        .unwrap();
+        assert_eq!(
+            renames,
+            vec![
+                (
+                    "Gemuese@Example.com".to_string(),
+                    "gemuese@example.com".to_string()
+                ),
+                (
+                    "obst@bücher.example".to_string(),
+                    "obst@xn--bcher-kva.example".to_string()
+                ),
+            ]
+        );
+        assert!(matches!(
+            super::list_renames(&[
+                list(1, "Gemuese@example.com"),
+                list(2, "gemuese@example.com")
+            ]),
+            Err(error::Error::DbMigrationConflict { .. })
+        ));
+    }
+}
@@ -0,0 +1,852 @@
+use super::{migrations, subscription_outcome, Storage, StoredSubscription};
+use crate::{address, error, types};
+use mysql::{params, prelude::Queryable};
+use snafu::ResultExt;
+
+/// MySQL database behind a pool that lives as long as the storage. With
+/// health checks on, the pool pings every connection it hands out and
+/// reconnects it if the ping fails.
+pub struct MySqlStorage {
+    pool: mysql::Pool,
+    acquire_timeout: std::time::Duration,
+}
+
+impl MySqlStorage {
+    /// Connects to `url`, `password` replaces the one of the url.
+    pub fn open(
+        url: &str,
+        password: Option<&str>,
+        config: &types::DbPoolConfig,
+    ) -> error::Result<MySqlStorage> {
+        let opts = mysql::Opts::from_url(url).context(error::DbUrlError {})?;
+        let connect_timeout = std::time::Duration::from_secs(config.connect_timeout_seconds);
+        let mut opts =
+            mysql::OptsBuilder::from_opts(opts).tcp_connect_timeout(Some(connect_timeout));
+        if let Some(password) = password {
+            opts = opts.pass(Some(password));
+        }
+        let mut pool =
+            mysql::Pool::new_manual(config.min_connections, config.max_connections, opts)
+                .context(error::DbConnectionError {})?;
+        pool.check_health(config.health_check);
+        Ok(MySqlStorage {
+            pool,
+            acquire_timeout: std::time::Duration::from_secs(config.acquire_timeout_seconds),
+        })
+    }
+
+    /// Waits at most the acquire timeout for a free connection of the pool.
+    fn get_conn(&self) -> error::Result<mysql::PooledConn> {
+        let timeout_ms = self.acquire_timeout.as_millis().min(u128::from(u32::MAX)) as u32;
+        self.pool
+            .try_get_conn(timeout_ms)
+            .context(error::DbConnectionError {})
+    }
+
+    fn with_transaction<T, F>(&self, operation: F) -> error::Result<T>
+    where
+        F: FnOnce(&mut mysql::Transaction) -> error::Result<T>,
+    {
+        let mut connection = self.get_conn()?;
+        let mut transaction = connection
+            .start_transaction(mysql::TxOpts::default())
+            .context(error::DbStartTransactionError {})?;
+        match operation(&mut transaction) {
+            Ok(result) => {
+                transaction
+                    .commit()
+                    .context(error::DbCommitTransactionError {})?;
+                Ok(result)
+            }
+            Err(err) => {
+                transaction
+                    .rollback()
+                    .context(error::DbRollbackTransactionError {})?;
+                Err(err)
+            }
+        }
+    }
+}
+
+impl Storage for MySqlStorage {
+    fn schema_version(&self) -> error::Result<u32> {
+        let mut connection = self.get_conn()?;
+        schema_version(&mut connection)
+    }
+
+    fn migrate(&self) -> error::Result<std::vec::Vec<u32>> {
+        let mut connection = self.get_conn()?;
+        let current = schema_version(&mut connection)?;
+        let pending = migrations::pending(migrations::MYSQL, current)?;
+        let create_version_table_stmt = r"CREATE TABLE IF NOT EXISTS schema_version (
+                                            version INTEGER NOT NULL PRIMARY KEY,
+                                            name VARCHAR(100) NOT NULL,
+                                            applied TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+                                          )";
+        connection
+            .query_drop(create_version_table_stmt)
+            .context(error::DbExecuteError {
+                statement: create_version_table_stmt,
+            })?;
+        let recorded = recorded_version(&mut connection)?;
+        for migration in &migrations::MYSQL[recorded as usize..current as usize] {
+            record_migration(&mut connection, migration)?;
+        }
+        let mut applied = std::vec::Vec::new();
+        for migration in pending {
+            // MySQL commits DDL implicitly, a failing migration has to be repaired by hand
+            for statement in migration.statements() {
+                connection
+                    .query_drop(statement)
+                    .context(error::DbExecuteError { statement })?;
+            }
+            // the step and the record of the migration commit together
+            let mut transaction = connection
+                .start_transaction(mysql::TxOpts::default())
+                .context(error::DbStartTransactionError {})?;
+            if let Some(step) = migration.step {
+                run_step(&mut transaction, step)?;
+            }
+            record_migration(&mut transaction, migration)?;
+            transaction
+                .commit()
+                .context(error::DbCommitTransactionError {})?;
+            applied.push(migration.version);
+        }
+        Ok(applied)
+    }
+
+    fn insert_subscriptions(
+        &self,
+        list_name: &str,
+        subscriptions: std::vec::Vec<types::Subscription>,
+        request: &str,
+        cooldown_minutes: u32,
+        expiry_hours: u32,
+    ) -> error::Result<(types::MailingList, std::vec::Vec<StoredSubscription>)> {
+        self.with_transaction(|transaction| {
+            let list = get_enabled_mailing_list(transaction, list_name)?;
+            let delete_expired_stmt =
+                r"DELETE FROM subscriptions WHERE timestamp < NOW() - INTERVAL :hours HOUR";
+            transaction
+                .exec_drop(delete_expired_stmt, params! { "hours" => expiry_hours })
+                .context(error::DbExecuteError {
+                    statement: delete_expired_stmt,
+                })?;
+            let mut stored = std::vec::Vec::new();
+            for mut subscription in subscriptions.into_iter() {
+                let outcome = store_subscription(
+                    transaction,
+                    &list,
+                    &mut subscription,
+                    request,
+                    cooldown_minutes,
+                )?;
+                stored.push((subscription, outcome));
+            }
+            Ok((list, stored))
+        })
+    }
+
+    fn discard_subscriptions(&self, tokens: &[uuid::Uuid]) -> error::Result<()> {
+        self.with_transaction(|transaction| {
+            let delete_subscription_stmt = r"DELETE FROM subscriptions WHERE uuid = :uuid";
+            for token in tokens {
+                transaction
+                    .exec_drop(delete_subscription_stmt, params! { "uuid" => token })
+                    .context(error::DbExecuteError {
+                        statement: delete_subscription_stmt,
+                    })?;
+            }
+            Ok(())
+        })
+    }
+
+    fn get_list(&self, list_name: &str) -> error::Result<types::MailingList> {
+        self.with_transaction(|transaction| get_mailing_list(transaction, list_name))
+    }
+
+    fn create_list(&self, list_name: &str, settings: &types::ListSettings) -> error::Result<()> {
+        self.with_transaction(|transaction| {
+            if get_mailing_list(transaction, list_name).is_ok() {
+                return Err(error::Error::DbMailingListAlreadyExists {
+                    list_name: list_name.to_string(),
+                });
+            }
+            let mut list = types::MailingList::new(list_name);
+            settings.apply(&mut list);
+            let insert_list_stmt = r"INSERT INTO mailing_lists
+                                       (title, email, enabled, language,
+                                        archive_url, help_url, unsubscribe_url,
+                                        fold_case, strip_subaddress)
+                                     VALUES (:title, :email, :enabled, :language,
+                                             :archive_url, :help_url, :unsubscribe_url,
+                                             :fold_case, :strip_subaddress)";
+            transaction
+                .exec_drop(insert_list_stmt, list_params(&list))
+                .context(error::DbExecuteError {
+                    statement: insert_list_stmt,
+                })?;
+            Ok(())
+        })
+    }
+
+    fn update_list(&self, list_name: &str, settings: &types::ListSettings) -> error::Result<()> {
+        self.with_transaction(|transaction| {
+            let mut list = get_mailing_list(transaction, list_name)?;
+            settings.apply(&mut list);
+            let update_list_stmt = r"UPDATE mailing_lists
+                                     SET title = :title, enabled = :enabled, language = :language,
+                                         archive_url = :archive_url, help_url = :help_url,
+                                         unsubscribe_url = :unsubscribe_url,
+                                         fold_case = :fold_case,
+                                         strip_subaddress = :strip_subaddress
+                                     WHERE email = :email";
+            transaction
+                .exec_drop(update_list_stmt, list_params(&list))
+                .context(error::DbExecuteError {
+                    statement: update_list_stmt,
+                })?;
+            Ok(())
+        })
+    }
+
+    fn delete_list(&self, list_name: &str) -> error::Result<()> {
+        self.with_transaction(|transaction| {
+            let list = get_mailing_list(transaction, list_name)?;
+            let delete_list_stmt = r"DELETE FROM mailing_lists WHERE id = :id";
+            transaction
+                .exec_drop(delete_list_stmt, params! { "id" => list.id })
+                .context(error::DbExecuteError {
+                    statement: delete_list_stmt,
+                })?;
+            Ok(())
+        })
+    }
+
+    fn list_members(
+        &self,
+        list_name: &str,
+        query: &types::MemberQuery,
+    ) -> error::Result<types::MemberPage> {
+        self.with_transaction(|transaction| {
+            let list = get_mailing_list(transaction, list_name)?;
+            let count_members_stmt = r"SELECT COUNT(*) FROM users WHERE list_id = :list_id";
+            let total: u64 = transaction
+                .exec_first(count_members_stmt, params! { "list_id" => list.id })
+                .context(error::DbExecuteError {
+                    statement: count_members_stmt,
+                })?
+                .unwrap_or(0);
+            let get_members_stmt = r"SELECT original_email, enabled FROM users
+                                     WHERE list_id = :list_id
+                                     ORDER BY email LIMIT :limit OFFSET :offset";
+            let members = transaction
+                .exec_map(
+                    get_members_stmt,
+                    params! {
+                        "list_id" => list.id,
+                        "limit" => query.limit,
+                        "offset" => query.offset,
+                    },
+                    |(email, enabled)| types::Member { email, enabled },
+                )
+                .context(error::DbExecuteError {
+                    statement: get_members_stmt,
+                })?;
+            Ok(types::MemberPage {
+                total,
+                offset: query.offset,
+                members,
+            })
+        })
+    }
+
+    fn add_member(
+        &self,
+        list_name: &str,
+        email: &address::Address,
+        enabled: bool,
+    ) -> error::Result<types::MailingList> {
+        self.with_transaction(|transaction| {
+            let list = get_mailing_list(transaction, list_name)?;
+            insert_user(transaction, list.id, email, enabled)?;
+            Ok(list)
+        })
+    }
+
+    fn update_member(
+        &self,
+        list_name: &str,
+        email: &address::Address,
+        enabled: Option<bool>,
+    ) -> error::Result<()> {
+        self.with_transaction(|transaction| {
+            let list = get_mailing_list(transaction, list_name)?;
+            if !is_member(transaction, list.id, &email.normalized)? {
+                return Err(error::Error::DbMemberDoesNotExist {
+                    email: email.original.clone(),
+                    list_name: list_name.to_string(),
+                });
+            }
+            let update_user_stmt = match enabled {
+                Some(_) => {
+                    r"UPDATE users SET enabled = :enabled
+                      WHERE list_id = :list_id AND email = :email"
+                }
+                None => r"DELETE FROM users WHERE list_id = :list_id AND email = :email",
+            };
+            let email = &email.normalized;
+            let params = match enabled {
+                Some(enabled) => {
+                    params! { "list_id" => list.id, "email" => email, "enabled" => enabled }
+                }
+                None => params! { "list_id" => list.id, "email" => email },
+            };
+            transaction
+                .exec_drop(update_user_stmt, params)
+                .context(error::DbExecuteError {
+                    statement: update_user_stmt,
+                })?;
+            Ok(())
+        })
+    }
+
+    fn pending_subscriptions(&self, expiry_hours: u32) -> error::Result<u64> {
+        let mut connection = self.get_conn()?;
+        let count_subscriptions_stmt = r"SELECT COUNT(*) FROM subscriptions
+                                         WHERE timestamp >= NOW() - INTERVAL :hours HOUR";
+        let count: Option<u64> = connection
+            .exec_first(
+                count_subscriptions_stmt,
+                params! { "hours" => expiry_hours },
+            )
+            .context(error::DbExecuteError {
+                statement: count_subscriptions_stmt,
+            })?;
+        Ok(count.unwrap_or(0))
+    }
+
+    fn get_subscription(
+        &self,
+        token: &uuid::Uuid,
+        expiry_hours: u32,
+    ) -> error::Result<(types::MailingList, types::Subscription)> {
+        self.with_transaction(|transaction| get_subscription(transaction, token, expiry_hours))
+    }
+
+    fn confirm_subscription(
+        &self,
+        token: &uuid::Uuid,
+        expiry_hours: u32,
+    ) -> error::Result<(types::MailingList, types::Subscription)> {
+        self.with_transaction(|transaction| {
+            let (list, subscription) = get_subscription(transaction, token, expiry_hours)?;
+            match subscription.action {
+                types::SubscriptionAction::Subscribe => {
+                    insert_user(transaction, list.id, &subscription.email, true)?;
+                }
+                types::SubscriptionAction::Unsubscribe => {
+                    let delete_user_stmt =
+                        r"DELETE FROM users WHERE list_id = :list_id AND email = :email";
+                    transaction
+                        .exec_drop(
+                            delete_user_stmt,
+                            params! {
+                                "list_id" => list.id,
+                                "email" => &subscription.email.normalized,
+                            },
+                        )
+                        .context(error::DbExecuteError {
+                            statement: delete_user_stmt,
+                        })?;
+                }
+            }
+            let delete_subscription_stmt = r"DELETE FROM subscriptions WHERE uuid = :uuid";
+            transaction
+                .exec_drop(delete_subscription_stmt, params! { "uuid" => *token })
+                .context(error::DbExecuteError {
+                    statement: delete_subscription_stmt,
+                })?;
+            Ok((list, subscription))
+        })
+    }
+
+    fn get_post_recipients(
+        &self,
+        list_name: &str,
+        sender: &address::Address,
+    ) -> error::Result<(types::MailingList, std::vec::Vec<String>)> {
+        self.with_transaction(|transaction| {
+            let list = get_enabled_mailing_list(transaction, list_name)?;
+            let get_sender_stmt = r"SELECT 1 FROM users
+                                    WHERE list_id = :list_id AND email = :email AND enabled";
+            let sender_enabled: Option<i32> = transaction
+                .exec_first(
+                    get_sender_stmt,
+                    params! { "list_id" => list.id, "email" => &sender.normalized },
+                )
+                .context(error::DbExecuteError {
+                    statement: get_sender_stmt,
+                })?;
+            if sender_enabled.is_none() {
+                return Err(error::Error::PostSenderNotMember {
+                    sender: sender.original.clone(),
+                    list_name: list_name.to_string(),
+                });
+            }
+            let get_recipients_stmt =
+                r"SELECT original_email FROM users WHERE list_id = :list_id AND enabled";
+            let recipients: std::vec::Vec<String> = transaction
+                .exec(get_recipients_stmt, params! { "list_id" => list.id })
+                .context(error::DbExecuteError {
+                    statement: get_recipients_stmt,
+                })?;
+            let recipients = recipients
+                .iter()
+                .map(|email| address::delivery_address(email))
+                .collect();
+            Ok((list, recipients))
+        })
+    }
+}
+
+fn schema_version(connection: &mut mysql::PooledConn) -> error::Result<u32> {
+    let recorded = recorded_version(connection)?;
+    if recorded > 0 {
+        return Ok(recorded);
+    }
+    if table_exists(connection, "mailing_lists")? {
+        return Ok(migrations::UNRECORDED_VERSION);
+    }
+    Ok(0)
+}
+
+fn recorded_version(connection: &mut mysql::PooledConn) -> error::Result<u32> {
+    if !table_exists(connection, "schema_version")? {
+        return Ok(0);
+    }
+    let get_version_stmt = r"SELECT COALESCE(MAX(version), 0) FROM schema_version";
+    let version: Option<u32> =
+        connection
+            .query_first(get_version_stmt)
+            .context(error::DbExecuteError {
+                statement: get_version_stmt,
+            })?;
+    Ok(version.unwrap_or(0))
+}
+
+fn record_migration(
+    connection: &mut impl Queryable,
+    migration: &migrations::Migration,
+) -> error::Result<()> {
+    let insert_version_stmt =
+        r"INSERT INTO schema_version (version, name) VALUES (:version, :name)";
+    connection
+        .exec_drop(
+            insert_version_stmt,
+            params! { "version" => migration.version, "name" => migration.name },
+        )
+        .context(error::DbExecuteError {
+            statement: insert_version_stmt,
+        })
+}
+
+fn run_step(transaction: &mut mysql::Transaction, step: migrations::Step) -> error::Result<()> {
+    match step {
+        migrations::Step::NormalizeAddresses => normalize_addresses(transaction),
+        migrations::Step::DropTextualTokens => drop_textual_tokens(transaction),
+    }
+}
+
+/// Only rows are deleted that are textual for sure, a pattern in SQL would
+/// match binary tokens, too.
+fn drop_textual_tokens(transaction: &mut mysql::Transaction) -> error::Result<()> {
+    let get_tokens_stmt = r"SELECT uuid FROM subscriptions";
+    let tokens: std::vec::Vec<std::vec::Vec<u8>> =
+        transaction
+            .query(get_tokens_stmt)
+            .context(error::DbExecuteError {
+                statement: get_tokens_stmt,
+            })?;
+    let delete_subscription_stmt = r"DELETE FROM subscriptions WHERE uuid = :uuid";
+    let mut dropped = 0;
+    for token in tokens
+        .iter()
+        .filter(|token| migrations::is_textual_token(token))
+    {
+        transaction
+            .exec_drop(delete_subscription_stmt, params! { "uuid" => token })
+            .context(error::DbExecuteError {
+                statement: delete_subscription_stmt,
+            })?;
+        dropped += 1;
+    }
+    log::info!(
+        "Dropped {} of {} pending requests, their tokens were stored as text",
+        dropped,
+        tokens.len()
+    );
+    Ok(())
+}
+
+/// Reading, deleting and renaming the addresses of members and of pending
+/// requests.
+const ADDRESS_STATEMENTS: &[(&str, &str, &str)] = &[
+    (
+        r"SELECT list_id, email, original_email FROM users",
+        r"DELETE FROM users WHERE list_id = :list_id AND email = :email",
+        r"UPDATE users SET email = :normalized WHERE list_id = :list_id AND email = :email",
+    ),
+    (
+        r"SELECT list_id, email, original_email FROM subscriptions",
+        r"DELETE FROM subscriptions WHERE list_id = :list_id AND email = :email",
+        r"UPDATE subscriptions SET email = :normalized
+          WHERE list_id = :list_id AND email = :email",
+    ),
+];
+
+fn normalize_addresses(transaction: &mut mysql::Transaction) -> error::Result<()> {
+    let get_lists_stmt = r"SELECT id, email, fold_case, strip_subaddress FROM mailing_lists";
+    let lists = transaction
+        .query_map(
+            get_lists_stmt,
+            |(id, email, fold_case, strip_subaddress)| migrations::StoredList {
+                id,
+                email,
+                rules: address::LocalPartRules {
+                    fold_case,
+                    strip_subaddress,
+                },
+            },
+        )
+        .context(error::DbExecuteError {
+            statement: get_lists_stmt,
+        })?;
+    let renames = migrations::list_renames(&lists)?;
+    let rename_list_stmt = r"UPDATE mailing_lists SET email = :normalized WHERE email = :email";
+    for (email, normalized) in renames.iter() {
+        transaction
+            .exec_drop(
+                rename_list_stmt,
+                params! { "email" => email, "normalized" => normalized },
+            )
+            .context(error::DbExecuteError {
+                statement: rename_list_stmt,
+            })?;
+    }
+    let (mut updated, mut dropped) = (0, 0);
+    for (get_stmt, delete_stmt, update_stmt) in ADDRESS_STATEMENTS {
+        let rows = transaction
+            .query_map(get_stmt, |(list_id, email, original)| {
+                migrations::StoredAddress {
+                    list_id,
+                    email,
+                    original,
+                }
+            })
+            .context(error::DbExecuteError {
+                statement: *get_stmt,
+            })?;
+        let normalization = migrations::address_normalization(&lists, rows)?;
+        for (list_id, email) in normalization.duplicates.iter() {
+            transaction
+                .exec_drop(
+                    delete_stmt,
+                    params! { "list_id" => list_id, "email" => email },
+                )
+                .context(error::DbExecuteError {
+                    statement: *delete_stmt,
+                })?;
+        }
+        for (list_id, email, normalized) in normalization.updates.iter() {
+            transaction
+                .exec_drop(
+                    update_stmt,
+                    params! {
+                        "list_id" => list_id,
+                        "email" => email,
+                        "normalized" => normalized,
+                    },
+                )
+                .context(error::DbExecuteError {
+                    statement: *update_stmt,
+                })?;
+        }
+        updated += normalization.updates.len();
+        dropped += normalization.duplicates.len();
+    }
+    log::info!(
+        "Normalized {} list names and {} addresses, dropped {} duplicates",
+        renames.len(),
+        updated,
+        dropped
+    );
+    Ok(())
+}
+
+fn table_exists(connection: &mut mysql::PooledConn, table: &str) -> error::Result<bool> {
+    let table_exists_stmt = r"SELECT COUNT(*) FROM information_schema.tables
+                              WHERE table_schema = DATABASE() AND table_name = :table";
+    let count: Option<u32> = connection
+        .exec_first(table_exists_stmt, params! { "table" => table })
+        .context(error::DbExecuteError {
+            statement: table_exists_stmt,
+        })?;
+    Ok(count.unwrap_or(0) > 0)
+}
+
+fn list_params(list: &types::MailingList) -> mysql::Params {
+    params! {
+        "title" => &list.title,
+        "email" => &list.email,
+        "enabled" => list.enabled,
+        "language" => &list.language,
+        "archive_url" => &list.archive_url,
+        "help_url" => &list.help_url,
+        "unsubscribe_url" => &list.unsubscribe_url,
+        "fold_case" => list.fold_case,
+        "strip_subaddress" => list.strip_subaddress,
+    }
+}
+
+/// Inserts or renews the pending request, a renewed one gets the token of
+/// the stored request.
+fn store_subscription(
+    transaction: &mut mysql::Transaction,
+    list: &types::MailingList,
+    subscription: &mut types::Subscription,
+    request: &str,
+    cooldown_minutes: u32,
+) -> error::Result<types::SubscriptionOutcome> {
+    let get_pending_stmt = r"SELECT uuid, action,
+                                    confirmation_sent > NOW() - INTERVAL :minutes MINUTE
+                             FROM subscriptions WHERE list_id = :list_id AND email = :email
+                             FOR UPDATE";
+    let pending: Option<(uuid::Uuid, String, bool)> = transaction
+        .exec_first(
+            get_pending_stmt,
+            params! {
+                "list_id" => list.id,
+                "email" => &subscription.email.normalized,
+                "minutes" => cooldown_minutes,
+            },
+        )
+        .context(error::DbExecuteError {
+            statement: get_pending_stmt,
+        })?;
+    let member = is_member(transaction, list.id, &subscription.email.normalized)?;
+    let outcome = subscription_outcome(
+        subscription.action,
+        member,
+        pending.as_ref().and_then(|(_, action, sent_recently)| {
+            types::SubscriptionAction::parse(action).map(|action| (action, *sent_recently))
+        }),
+    );
+    match outcome {
+        types::SubscriptionOutcome::Requested => {
+            if let Some((uuid, _, _)) = &pending {
+                let delete_subscription_stmt = r"DELETE FROM subscriptions WHERE uuid = :uuid";
+                transaction
+                    .exec_drop(delete_subscription_stmt, params! { "uuid" => *uuid })
+                    .context(error::DbExecuteError {
+                        statement: delete_subscription_stmt,
+                    })?;
+            }
+            let insert_statement = r"INSERT INTO subscriptions
+                             (uuid, list_id, email, original_email, request, action,
+                              confirmation_sent)
+                           VALUES (:uuid, :list_id, :email, :original_email, :request, :action,
+                                   NOW())";
+            transaction
+                .exec_drop(
+                    insert_statement,
+                    params! {
+                        "uuid" => subscription.uuid,
+                        "list_id" => list.id,
+                        "email" => &subscription.email.normalized,
+                        "original_email" => &subscription.email.original,
+                        "request" => request,
+                        "action" => subscription.action.as_str(),
+                    },
+                )
+                .context(error::DbExecuteError {
+                    statement: insert_statement,
+                })?;
+        }
+        types::SubscriptionOutcome::Renewed | types::SubscriptionOutcome::Pending => {
+            let (uuid, _, _) = pending.unwrap();
+            let renew_statement = r"UPDATE subscriptions
+                                    SET original_email = :original_email, request = :request,
+                                        timestamp = NOW(),
+                                        confirmation_sent = IF(:resend, NOW(), confirmation_sent)
+                                    WHERE uuid = :uuid";
+            transaction
+                .exec_drop(
+                    renew_statement,
+                    params! {
+                        "uuid" => uuid,
+                        "original_email" => &subscription.email.original,
+                        "request" => request,
+                        "resend" => outcome == types::SubscriptionOutcome::Renewed,
+                    },
+                )
+                .context(error::DbExecuteError {
+                    statement: renew_statement,
+                })?;
+            subscription.uuid = uuid;
+        }
+        types::SubscriptionOutcome::AlreadySubscribed
+        | types::SubscriptionOutcome::NotSubscribed => {}
+    }
+    Ok(outcome)
+}
+
+/// The pending subscription with the given token, locked until the end of
+/// the transaction.
+fn get_subscription(
+    transaction: &mut mysql::Transaction,
+    token: &uuid::Uuid,
+    expiry_hours: u32,
+) -> error::Result<(types::MailingList, types::Subscription)> {
+    let get_subscription_stmt = r"SELECT l.email, s.email, s.original_email,
+                                         s.action, s.timestamp < NOW() - INTERVAL :hours HOUR
+                                  FROM subscriptions s
+                                  JOIN mailing_lists l ON l.id = s.list_id
+                                  WHERE s.uuid = :uuid FOR UPDATE";
+    let (list_name, normalized, original, action, expired): (String, String, String, String, bool) =
+        transaction
+            .exec_first(
+                get_subscription_stmt,
+                params! { "uuid" => *token, "hours" => expiry_hours },
+            )
+            .context(error::DbExecuteError {
+                statement: get_subscription_stmt,
+            })?
+            .ok_or(error::Error::SubscriptionDoesNotExist {
+                token: token.to_string(),
+            })?;
+    if expired {
+        return Err(error::Error::SubscriptionExpired {
+            token: token.to_string(),
+        });
+    }
+    let action = types::SubscriptionAction::parse(&action).ok_or(
+        error::Error::SubscriptionUnknownAction {
+            token: token.to_string(),
+            action: action.clone(),
+        },
+    )?;
+    let list = get_mailing_list(transaction, &list_name)?;
+    Ok((
+        list,
+        types::Subscription {
+            email: address::Address {
+                original,
+                normalized,
+            },
+            uuid: *token,
+            action,
+        },
+    ))
+}
+
+/// Adds the member or updates it, it keeps the form of the address it used last.
+fn insert_user(
+    transaction: &mut mysql::Transaction,
+    list_id: i32,
+    email: &address::Address,
+    enabled: bool,
+) -> error::Result<()> {
+    let insert_user_stmt = r"INSERT INTO users (list_id, email, original_email, password, enabled)
+                             VALUES (:list_id, :email, :original_email, '', :enabled)
+                             ON DUPLICATE KEY UPDATE
+                               enabled = :enabled, original_email = :original_email";
+    transaction
+        .exec_drop(
+            insert_user_stmt,
+            params! {
+                "list_id" => list_id,
+                "email" => &email.normalized,
+                "original_email" => &email.original,
+                "enabled" => enabled,
+            },
+        )
+        .context(error::DbExecuteError {
+            statement: insert_user_stmt,
+        })
+}
+
+fn is_member(
+    transaction: &mut mysql::Transaction,
+    list_id: i32,
+    email: &str,
+) -> error::Result<bool> {
+    let get_member_stmt = r"SELECT 1 FROM users WHERE list_id = :list_id AND email = :email";
+    let member: Option<i32> = transaction
+        .exec_first(
+            get_member_stmt,
+            params! { "list_id" => list_id, "email" => email },
+        )
+        .context(error::DbExecuteError {
+            statement: get_member_stmt,
+        })?;
+    Ok(member.is_some())
+}
+
+fn get_mailing_list(
+    transaction: &mut mysql::Transaction,
+    list_name: &str,
+) -> error::Result<types::MailingList> {
+    let get_list_stmt = r"SELECT id, title, email, enabled, language,
+                                 archive_url, help_url, unsubscribe_url,
+                                 fold_case, strip_subaddress
+                          FROM mailing_lists WHERE email = :email";
+    let prep_get_list_stmt = transaction
+        .prep(get_list_stmt)
+        .context(error::DbPrepareError {
+            statement: get_list_stmt,
+        })?;
+    transaction
+        .exec_first(&prep_get_list_stmt, params! { "email" => list_name })
+        .context(error::DbExecuteError {
+            statement: get_list_stmt,
+        })?
+        .map(
+            |(
+                id,
+                title,
+                email,
+                enabled,
+                language,
+                archive_url,
+                help_url,
+                unsubscribe_url,
+                fold_case,
+                strip_subaddress,
+            )| types::MailingList {
+                id,
+                title,
+                email,
+                enabled,
+                language,
+                archive_url,
+                help_url,
+                unsubscribe_url,
+                fold_case,
+                strip_subaddress,
+            },
+        )
+        .ok_or(error::Error::DbMailingListDoesNotExist {
+            list_name: list_name.to_string(),
+        })
+}
+
+fn get_enabled_mailing_list(
+    transaction: &mut mysql::Transaction,
+    list_name: &str,
+) -> error::Result<types::MailingList> {
+    let list = get_mailing_list(transaction, list_name)?;
+    list.check_enabled()?;
+    Ok(list)
+}
@@ -0,0 +1,865 @@
+use super::{migrations, subscription_outcome, Storage, StoredSubscription};
+use crate::{address, error, types};
+use rusqlite::{functions::FunctionFlags, named_params, OptionalExtension};
+use snafu::ResultExt;
+
+/// SQLite database in a single file.
+pub struct SqliteStorage {
+    connection: std::sync::Mutex<rusqlite::Connection>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &std::path::Path) -> error::Result<SqliteStorage> {
+        let connection = rusqlite::Connection::open(path).context(error::SqliteOpenError {
+            path: path.to_string_lossy().to_string(),
+        })?;
+        let setup_stmt = "PRAGMA foreign_keys = ON";
+        connection
+            .execute_batch(setup_stmt)
+            .context(error::SqliteExecuteError {
+                statement: setup_stmt,
+            })?;
+        connection
+            .busy_timeout(std::time::Duration::from_secs(5))
+            .context(error::SqliteExecuteError {
+                statement: "PRAGMA busy_timeout",
+            })?;
+        // converts the textual tokens of former releases when migrating
+        connection
+            .create_scalar_function(
+                "uuid_blob",
+                1,
+                FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+                |context| {
+                    let token: String = context.get(0)?;
+                    uuid::Uuid::parse_str(&token)
+                        .map_err(|err| rusqlite::Error::UserFunctionError(Box::new(err)))
+                },
+            )
+            .context(error::SqliteExecuteError {
+                statement: "uuid_blob",
+            })?;
+        Ok(SqliteStorage {
+            connection: std::sync::Mutex::new(connection),
+        })
+    }
+
+    fn with_transaction<T, F>(&self, operation: F) -> error::Result<T>
+    where
+        F: FnOnce(&rusqlite::Transaction) -> error::Result<T>,
+    {
+        let mut connection = self
+            .connection
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let transaction = connection
+            .transaction()
+            .context(error::SqliteExecuteError { statement: "BEGIN" })?;
+        // dropping the transaction without commit rolls it back
+        let result = operation(&transaction)?;
+        transaction.commit().context(error::SqliteExecuteError {
+            statement: "COMMIT",
+        })?;
+        Ok(result)
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn schema_version(&self) -> error::Result<u32> {
+        let connection = self
+            .connection
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        schema_version(&connection)
+    }
+
+    fn migrate(&self) -> error::Result<std::vec::Vec<u32>> {
+        let mut connection = self
+            .connection
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let current = schema_version(&connection)?;
+        let pending = migrations::pending(migrations::SQLITE, current)?;
+        let create_version_table_stmt = r"CREATE TABLE IF NOT EXISTS schema_version (
+                                            version INTEGER NOT NULL PRIMARY KEY,
+                                            name VARCHAR(100) NOT NULL,
+                                            applied TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+                                          )";
+        connection
+            .execute_batch(create_version_table_stmt)
+            .context(error::SqliteExecuteError {
+                statement: create_version_table_stmt,
+            })?;
+        let recorded = recorded_version(&connection)?;
+        for migration in &migrations::SQLITE[recorded as usize..current as usize] {
+            record_migration(&connection, migration)?;
+        }
+        let mut applied = std::vec::Vec::new();
+        for migration in pending {
+            let transaction = connection
+                .transaction()
+                .context(error::SqliteExecuteError { statement: "BEGIN" })?;
+            transaction
+                .execute_batch(migration.sql)
+                .context(error::SqliteExecuteError {
+                    statement: migration.sql,
+                })?;
+            if let Some(step) = migration.step {
+                run_step(&transaction, step)?;
+            }
+            record_migration(&transaction, migration)?;
+            transaction.commit().context(error::SqliteExecuteError {
+                statement: "COMMIT",
+            })?;
+            applied.push(migration.version);
+        }
+        Ok(applied)
+    }
+
+    fn insert_subscriptions(
+        &self,
+        list_name: &str,
+        subscriptions: std::vec::Vec<types::Subscription>,
+        request: &str,
+        cooldown_minutes: u32,
+        expiry_hours: u32,
+    ) -> error::Result<(types::MailingList, std::vec::Vec<StoredSubscription>)> {
+        self.with_transaction(|transaction| {
+            let list = get_mailing_list(transaction, list_name)?;
+            list.check_enabled()?;
+            let delete_expired_stmt =
+                r"DELETE FROM subscriptions WHERE timestamp < datetime('now', :expiry)";
+            transaction
+                .execute_named(
+                    delete_expired_stmt,
+                    named_params! { ":expiry": format!("-{} hours", expiry_hours) },
+                )
+                .context(error::SqliteExecuteError {
+                    statement: delete_expired_stmt,
+                })?;
+            let mut stored = std::vec::Vec::new();
+            for mut subscription in subscriptions.into_iter() {
+                let outcome = store_subscription(
+                    transaction,
+                    &list,
+                    &mut subscription,
+                    request,
+                    cooldown_minutes,
+                )?;
+                stored.push((subscription, outcome));
+            }
+            Ok((list, stored))
+        })
+    }
+
+    fn discard_subscriptions(&self, tokens: &[uuid::Uuid]) -> error::Result<()> {
+        self.with_transaction(|transaction| {
+            let delete_subscription_stmt = r"DELETE FROM subscriptions WHERE uuid = :uuid";
+            for token in tokens {
+                transaction
+                    .execute_named(delete_subscription_stmt, named_params! { ":uuid": token })
+                    .context(error::SqliteExecuteError {
+                        statement: delete_subscription_stmt,
+                    })?;
+            }
+            Ok(())
+        })
+    }
+
+    fn get_list(&self, list_name: &str) -> error::Result<types::MailingList> {
+        self.with_transaction(|transaction| get_mailing_list(transaction, list_name))
+    }
+
+    fn create_list(&self, list_name: &str, settings: &types::ListSettings) -> error::Result<()> {
+        self.with_transaction(|transaction| {
+            if get_mailing_list(transaction, list_name).is_ok() {
+                return Err(error::Error::DbMailingListAlreadyExists {
+                    list_name: list_name.to_string(),
+                });
+            }
+            let mut list = types::MailingList::new(list_name);
+            settings.apply(&mut list);
+            let insert_list_stmt = r"INSERT INTO mailing_lists
+                                       (title, email, enabled, language,
+                                        archive_url, help_url, unsubscribe_url,
+                                        fold_case, strip_subaddress)
+                                     VALUES (:title, :email, :enabled, :language,
+                                             :archive_url, :help_url, :unsubscribe_url,
+                                             :fold_case, :strip_subaddress)";
+            execute_with_list(transaction, insert_list_stmt, &list)
+        })
+    }
+
+    fn update_list(&self, list_name: &str, settings: &types::ListSettings) -> error::Result<()> {
+        self.with_transaction(|transaction| {
+            let mut list = get_mailing_list(transaction, list_name)?;
+            settings.apply(&mut list);
+            let update_list_stmt = r"UPDATE mailing_lists
+                                     SET title = :title, enabled = :enabled, language = :language,
+                                         archive_url = :archive_url, help_url = :help_url,
+                                         unsubscribe_url = :unsubscribe_url,
+                                         fold_case = :fold_case,
+                                         strip_subaddress = :strip_subaddress
+                                     WHERE email = :email";
+            execute_with_list(transaction, update_list_stmt, &list)
+        })
+    }
+
+    fn delete_list(&self, list_name: &str) -> error::Result<()> {
+        self.with_transaction(|transaction| {
+            let list = get_mailing_list(transaction, list_name)?;
+            let delete_list_stmt = r"DELETE FROM mailing_lists WHERE id = :id";
+            transaction
+                .execute_named(delete_list_stmt, named_params! { ":id": list.id })
+                .context(error::SqliteExecuteError {
+                    statement: delete_list_stmt,
+                })?;
+            Ok(())
+        })
+    }
+
+    fn list_members(
+        &self,
+        list_name: &str,
+        query: &types::MemberQuery,
+    ) -> error::Result<types::MemberPage> {
+        self.with_transaction(|transaction| {
+            let list = get_mailing_list(transaction, list_name)?;
+            let count_members_stmt = r"SELECT COUNT(*) FROM users WHERE list_id = :list_id";
+            let total: i64 = transaction
+                .query_row_named(
+                    count_members_stmt,
+                    named_params! { ":list_id": list.id },
+                    |row| row.get(0),
+                )
+                .context(error::SqliteExecuteError {
+                    statement: count_members_stmt,
+                })?;
+            let get_members_stmt = r"SELECT original_email, enabled FROM users
+                                     WHERE list_id = :list_id
+                                     ORDER BY email LIMIT :limit OFFSET :offset";
+            let mut statement =
+                transaction
+                    .prepare(get_members_stmt)
+                    .context(error::SqliteExecuteError {
+                        statement: get_members_stmt,
+                    })?;
+            let members = statement
+                .query_map_named(
+                    named_params! {
+                        ":list_id": list.id,
+                        ":limit": query.limit as i64,
+                        ":offset": query.offset as i64,
+                    },
+                    |row| {
+                        Ok(types::Member {
+                            email: row.get(0)?,
+                            enabled: row.get(1)?,
+                        })
+                    },
+                )
+                .and_then(|rows| rows.collect::<Result<std::vec::Vec<_>, _>>())
+                .context(error::SqliteExecuteError {
+                    statement: get_members_stmt,
+                })?;
+            Ok(types::MemberPage {
+                total: total as u64,
+                offset: query.offset,
+                members,
+            })
+        })
+    }
+
+    fn add_member(
+        &self,
+        list_name: &str,
+        email: &address::Address,
+        enabled: bool,
+    ) -> error::Result<types::MailingList> {
+        self.with_transaction(|transaction| {
+            let list = get_mailing_list(transaction, list_name)?;
+            insert_user(transaction, list.id, email, enabled)?;
+            Ok(list)
+        })
+    }
+
+    fn update_member(
+        &self,
+        list_name: &str,
+        email: &address::Address,
+        enabled: Option<bool>,
+    ) -> error::Result<()> {
+        self.with_transaction(|transaction| {
+            let list = get_mailing_list(transaction, list_name)?;
+            if !is_member(transaction, list.id, &email.normalized)? {
+                return Err(error::Error::DbMemberDoesNotExist {
+                    email: email.original.clone(),
+                    list_name: list_name.to_string(),
+                });
+            }
+            let result = match enabled {
+                Some(enabled) => {
+                    let update_user_stmt = r"UPDATE users SET enabled = :enabled
+                                             WHERE list_id = :list_id AND email = :email";
+                    transaction
+                        .execute_named(
+                            update_user_stmt,
+                            named_params! {
+                                ":list_id": list.id,
+                                ":email": email.normalized,
+                                ":enabled": enabled,
+                            },
+                        )
+                        .context(error::SqliteExecuteError {
+                            statement: update_user_stmt,
+                        })
+                }
+                None => delete_user(transaction, list.id, &email.normalized),
+            };
+            result.map(|_| ())
+        })
+    }
+
+    fn pending_subscriptions(&self, expiry_hours: u32) -> error::Result<u64> {
+        let connection = self
+            .connection
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let count_subscriptions_stmt =
+            r"SELECT COUNT(*) FROM subscriptions WHERE timestamp >= datetime('now', :expiry)";
+        let count: i64 = connection
+            .query_row_named(
+                count_subscriptions_stmt,
+                named_params! { ":expiry": format!("-{} hours", expiry_hours) },
+                |row| row.get(0),
+            )
+            .context(error::SqliteExecuteError {
+                statement: count_subscriptions_stmt,
+            })?;
+        Ok(count as u64)
+    }
+
+    fn get_subscription(
+        &self,
+        token: &uuid::Uuid,
+        expiry_hours: u32,
+    ) -> error::Result<(types::MailingList, types::Subscription)> {
+        self.with_transaction(|transaction| get_subscription(transaction, token, expiry_hours))
+    }
+
+    fn confirm_subscription(
+        &self,
+        token: &uuid::Uuid,
+        expiry_hours: u32,
+    ) -> error::Result<(types::MailingList, types::Subscription)> {
+        self.with_transaction(|transaction| {
+            let (list, subscription) = get_subscription(transaction, token, expiry_hours)?;
+            match subscription.action {
+                types::SubscriptionAction::Subscribe => {
+                    insert_user(transaction, list.id, &subscription.email, true)?;
+                }
+                types::SubscriptionAction::Unsubscribe => {
+                    delete_user(transaction, list.id, &subscription.email.normalized)?;
+                }
+            }
+            let delete_subscription_stmt = r"DELETE FROM subscriptions WHERE uuid = :uuid";
+            transaction
+                .execute_named(delete_subscription_stmt, named_params! { ":uuid": token })
+                .context(error::SqliteExecuteError {
+                    statement: delete_subscription_stmt,
+                })?;
+            Ok((list, subscription))
+        })
+    }
+
+    fn get_post_recipients(
+        &self,
+        list_name: &str,
+        sender: &address::Address,
+    ) -> error::Result<(types::MailingList, std::vec::Vec<String>)> {
+        self.with_transaction(|transaction| {
+            let list = get_mailing_list(transaction, list_name)?;
+            list.check_enabled()?;
+            let get_sender_stmt = r"SELECT 1 FROM users
+                                    WHERE list_id = :list_id AND email = :email AND enabled";
+            let sender_enabled: Option<i32> = transaction
+                .query_row_named(
+                    get_sender_stmt,
+                    named_params! { ":list_id": list.id, ":email": sender.normalized },
+                    |row| row.get(0),
+                )
+                .optional()
+                .context(error::SqliteExecuteError {
+                    statement: get_sender_stmt,
+                })?;
+            if sender_enabled.is_none() {
+                return Err(error::Error::PostSenderNotMember {
+                    sender: sender.original.clone(),
+                    list_name: list_name.to_string(),
+                });
+            }
+            let get_recipients_stmt =
+                r"SELECT original_email FROM users WHERE list_id = :list_id AND enabled";
+            let mut statement =
+                transaction
+                    .prepare(get_recipients_stmt)
+                    .context(error::SqliteExecuteError {
+                        statement: get_recipients_stmt,
+                    })?;
+            let recipients = statement
+                .query_map_named(named_params! { ":list_id": list.id }, |row| {
+                    row.get(0)
+                        .map(|email: String| address::delivery_address(&email))
+                })
+                .and_then(|rows| rows.collect::<Result<std::vec::Vec<String>, _>>())
+                .context(error::SqliteExecuteError {
+                    statement: get_recipients_stmt,
+                })?;
+            Ok((list, recipients))
+        })
+    }
+}
+
+fn schema_version(connection: &rusqlite::Connection) -> error::Result<u32> {
+    let recorded = recorded_version(connection)?;
+    if recorded > 0 {
+        return Ok(recorded);
+    }
+    if table_exists(connection, "mailing_lists")? {
+        return Ok(migrations::UNRECORDED_VERSION);
+    }
+    Ok(0)
+}
+
+fn recorded_version(connection: &rusqlite::Connection) -> error::Result<u32> {
+    if !table_exists(connection, "schema_version")? {
+        return Ok(0);
+    }
+    let get_version_stmt = r"SELECT COALESCE(MAX(version), 0) FROM schema_version";
+    connection
+        .query_row(get_version_stmt, rusqlite::NO_PARAMS, |row| row.get(0))
+        .context(error::SqliteExecuteError {
+            statement: get_version_stmt,
+        })
+}
+
+fn record_migration(
+    connection: &rusqlite::Connection,
+    migration: &migrations::Migration,
+) -> error::Result<()> {
+    let insert_version_stmt =
+        r"INSERT INTO schema_version (version, name) VALUES (:version, :name)";
+    connection
+        .execute_named(
+            insert_version_stmt,
+            named_params! { ":version": migration.version, ":name": migration.name },
+        )
+        .context(error::SqliteExecuteError {
+            statement: insert_version_stmt,
+        })?;
+    Ok(())
+}
+
+fn run_step(connection: &rusqlite::Connection, step: migrations::Step) -> error::Result<()> {
+    match step {
+        migrations::Step::NormalizeAddresses => normalize_addresses(connection),
+        // the textual tokens are converted by the statements
+        migrations::Step::DropTextualTokens => Ok(()),
+    }
+}
+
+/// Reading, deleting and renaming the addresses of members and of pending
+/// requests.
+const ADDRESS_STATEMENTS: &[(&str, &str, &str)] = &[
+    (
+        r"SELECT list_id, email, original_email FROM users",
+        r"DELETE FROM users WHERE list_id = :list_id AND email = :email",
+        r"UPDATE users SET email = :normalized WHERE list_id = :list_id AND email = :email",
+    ),
+    (
+        r"SELECT list_id, email, original_email FROM subscriptions",
+        r"DELETE FROM subscriptions WHERE list_id = :list_id AND email = :email",
+        r"UPDATE subscriptions SET email = :normalized
+          WHERE list_id = :list_id AND email = :email",
+    ),
+];
+
+fn normalize_addresses(connection: &rusqlite::Connection) -> error::Result<()> {
+    let get_lists_stmt = r"SELECT id, email, fold_case, strip_subaddress FROM mailing_lists";
+    let lists = connection
+        .prepare(get_lists_stmt)
+        .and_then(|mut statement| {
+            statement
+                .query_map(rusqlite::NO_PARAMS, |row| {
+                    Ok(migrations::StoredList {
+                        id: row.get(0)?,
+                        email: row.get(1)?,
+                        rules: address::LocalPartRules {
+                            fold_case: row.get(2)?,
+                            strip_subaddress: row.get(3)?,
+                        },
+                    })
+                })?
+                .collect::<Result<std::vec::Vec<_>, _>>()
+        })
+        .context(error::SqliteExecuteError {
+            statement: get_lists_stmt,
+        })?;
+    let renames = migrations::list_renames(&lists)?;
+    let rename_list_stmt = r"UPDATE mailing_lists SET email = :normalized WHERE email = :email";
+    for (email, normalized) in renames.iter() {
+        connection
+            .execute_named(
+                rename_list_stmt,
+                named_params! { ":email": email, ":normalized": normalized },
+            )
+            .context(error::SqliteExecuteError {
+                statement: rename_list_stmt,
+            })?;
+    }
+    let (mut updated, mut dropped) = (0, 0);
+    for (get_stmt, delete_stmt, update_stmt) in ADDRESS_STATEMENTS {
+        let rows = connection
+            .prepare(get_stmt)
+            .and_then(|mut statement| {
+                statement
+                    .query_map(rusqlite::NO_PARAMS, |row| {
+                        Ok(migrations::StoredAddress {
+                            list_id: row.get(0)?,
+                            email: row.get(1)?,
+                            original: row.get(2)?,
+                        })
+                    })?
+                    .collect::<Result<std::vec::Vec<_>, _>>()
+            })
+            .context(error::SqliteExecuteError {
+                statement: *get_stmt,
+            })?;
+        let normalization = migrations::address_normalization(&lists, rows)?;
+        for (list_id, email) in normalization.duplicates.iter() {
+            connection
+                .execute_named(
+                    delete_stmt,
+                    named_params! { ":list_id": list_id, ":email": email },
+                )
+                .context(error::SqliteExecuteError {
+                    statement: *delete_stmt,
+                })?;
+        }
+        for (list_id, email, normalized) in normalization.updates.iter() {
+            connection
+                .execute_named(
+                    update_stmt,
+                    named_params! {
+                        ":list_id": list_id,
+                        ":email": email,
+                        ":normalized": normalized,
+                    },
+                )
+                .context(error::SqliteExecuteError {
+                    statement: *update_stmt,
+                })?;
+        }
+        updated += normalization.updates.len();
+        dropped += normalization.duplicates.len();
+    }
+    log::info!(
+        "Normalized {} list names and {} addresses, dropped {} duplicates",
+        renames.len(),
+        updated,
+        dropped
+    );
+    Ok(())
+}
+
+fn table_exists(connection: &rusqlite::Connection, table: &str) -> error::Result<bool> {
+    let table_exists_stmt = r"SELECT COUNT(*) FROM sqlite_master
+                              WHERE type = 'table' AND name = :table";
+    let count: i64 = connection
+        .query_row_named(
+            table_exists_stmt,
+            named_params! { ":table": table },
+            |row| row.get(0),
+        )
+        .context(error::SqliteExecuteError {
+            statement: table_exists_stmt,
+        })?;
+    Ok(count > 0)
+}
+
+fn execute_with_list(
+    transaction: &rusqlite::Transaction,
+    statement: &'static str,
+    list: &types::MailingList,
+) -> error::Result<()> {
+    transaction
+        .execute_named(
+            statement,
+            named_params! {
+                ":title": list.title,
+                ":email": list.email,
+                ":enabled": list.enabled,
+                ":language": list.language,
+                ":archive_url": list.archive_url,
+                ":help_url": list.help_url,
+                ":unsubscribe_url": list.unsubscribe_url,
+                ":fold_case": list.fold_case,
+                ":strip_subaddress": list.strip_subaddress,
+            },
+        )
+        .context(error::SqliteExecuteError { statement })?;
+    Ok(())
+}
+
+/// Inserts or renews the pending request, a renewed one gets the token of
+/// the stored request.
+fn store_subscription(
+    transaction: &rusqlite::Transaction,
+    list: &types::MailingList,
+    subscription: &mut types::Subscription,
+    request: &str,
+    cooldown_minutes: u32,
+) -> error::Result<types::SubscriptionOutcome> {
+    let get_pending_stmt = r"SELECT uuid, action,
+                                    confirmation_sent > datetime('now', :cooldown)
+                             FROM subscriptions WHERE list_id = :list_id AND email = :email";
+    let pending: Option<(uuid::Uuid, String, Option<bool>)> = transaction
+        .query_row_named(
+            get_pending_stmt,
+            named_params! {
+                ":list_id": list.id,
+                ":email": subscription.email.normalized,
+                ":cooldown": format!("-{} minutes", cooldown_minutes),
+            },
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()
+        .context(error::SqliteExecuteError {
+            statement: get_pending_stmt,
+        })?;
+    let member = is_member(transaction, list.id, &subscription.email.normalized)?;
+    let outcome = subscription_outcome(
+        subscription.action,
+        member,
+        pending.as_ref().and_then(|(_, action, sent_recently)| {
+            types::SubscriptionAction::parse(action)
+                .map(|action| (action, sent_recently.unwrap_or(false)))
+        }),
+    );
+    match outcome {
+        types::SubscriptionOutcome::Requested => {
+            if let Some((uuid, _, _)) = &pending {
+                let delete_subscription_stmt = r"DELETE FROM subscriptions WHERE uuid = :uuid";
+                transaction
+                    .execute_named(delete_subscription_stmt, named_params! { ":uuid": uuid })
+                    .context(error::SqliteExecuteError {
+                        statement: delete_subscription_stmt,
+                    })?;
+            }
+            let insert_statement = r"INSERT INTO subscriptions
+                                       (uuid, list_id, email, original_email, request, action,
+                                        confirmation_sent)
+                                     VALUES (:uuid, :list_id, :email, :original_email,
+                                             :request, :action, CURRENT_TIMESTAMP)";
+            transaction
+                .execute_named(
+                    insert_statement,
+                    named_params! {
+                        ":uuid": subscription.uuid,
+                        ":list_id": list.id,
+                        ":email": subscription.email.normalized,
+                        ":original_email": subscription.email.original,
+                        ":request": request,
+                        ":action": subscription.action.as_str(),
+                    },
+                )
+                .context(error::SqliteExecuteError {
+                    statement: insert_statement,
+                })?;
+        }
+        types::SubscriptionOutcome::Renewed | types::SubscriptionOutcome::Pending => {
+            let (uuid, _, _) = pending.unwrap();
+            let renew_statement = r"UPDATE subscriptions
+                                    SET original_email = :original_email, request = :request,
+                                        timestamp = CURRENT_TIMESTAMP,
+                                        confirmation_sent = CASE WHEN :resend
+                                          THEN CURRENT_TIMESTAMP ELSE confirmation_sent END
+                                    WHERE uuid = :uuid";
+            transaction
+                .execute_named(
+                    renew_statement,
+                    named_params! {
+                        ":uuid": uuid,
+                        ":original_email": subscription.email.original,
+                        ":request": request,
+                        ":resend": outcome == types::SubscriptionOutcome::Renewed,
+                    },
+                )
+                .context(error::SqliteExecuteError {
+                    statement: renew_statement,
+                })?;
+            subscription.uuid = uuid;
+        }
+        types::SubscriptionOutcome::AlreadySubscribed
+        | types::SubscriptionOutcome::NotSubscribed => {}
+    }
+    Ok(outcome)
+}
+
+fn get_subscription(
+    transaction: &rusqlite::Transaction,
+    token: &uuid::Uuid,
+    expiry_hours: u32,
+) -> error::Result<(types::MailingList, types::Subscription)> {
+    let get_subscription_stmt = r"SELECT l.email, s.email, s.original_email,
+                                         s.action, s.timestamp < datetime('now', :expiry)
+                                  FROM subscriptions s
+                                  JOIN mailing_lists l ON l.id = s.list_id
+                                  WHERE s.uuid = :uuid";
+    let (list_name, normalized, original, action, expired): (String, String, String, String, bool) =
+        transaction
+            .query_row_named(
+                get_subscription_stmt,
+                named_params! {
+                    ":uuid": token,
+                    ":expiry": format!("-{} hours", expiry_hours),
+                },
+                |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                        row.get(4)?,
+                    ))
+                },
+            )
+            .optional()
+            .context(error::SqliteExecuteError {
+                statement: get_subscription_stmt,
+            })?
+            .ok_or(error::Error::SubscriptionDoesNotExist {
+                token: token.to_string(),
+            })?;
+    if expired {
+        return Err(error::Error::SubscriptionExpired {
+            token: token.to_string(),
+        });
+    }
+    let action = types::SubscriptionAction::parse(&action).ok_or(
+        error::Error::SubscriptionUnknownAction {
+            token: token.to_string(),
+            action: action.clone(),
+        },
+    )?;
+    let list = get_mailing_list(transaction, &list_name)?;
+    Ok((
+        list,
+        types::Subscription {
+            email: address::Address {
+                original,
+                normalized,
+            },
+            uuid: *token,
+            action,
+        },
+    ))
+}
+
+/// Adds the member or updates it, it keeps the form of the address it used last.
+fn insert_user(
+    transaction: &rusqlite::Transaction,
+    list_id: i32,
+    email: &address::Address,
+    enabled: bool,
+) -> error::Result<()> {
+    let insert_user_stmt = r"INSERT INTO users (list_id, email, original_email, password, enabled)
+                             VALUES (:list_id, :email, :original_email, '', :enabled)
+                             ON CONFLICT (list_id, email)
+                             DO UPDATE SET enabled = :enabled, original_email = :original_email";
+    transaction
+        .execute_named(
+            insert_user_stmt,
+            named_params! {
+                ":list_id": list_id,
+                ":email": email.normalized,
+                ":original_email": email.original,
+                ":enabled": enabled,
+            },
+        )
+        .context(error::SqliteExecuteError {
+            statement: insert_user_stmt,
+        })?;
+    Ok(())
+}
+
+fn delete_user(
+    transaction: &rusqlite::Transaction,
+    list_id: i32,
+    email: &str,
+) -> error::Result<usize> {
+    let delete_user_stmt = r"DELETE FROM users WHERE list_id = :list_id AND email = :email";
+    transaction
+        .execute_named(
+            delete_user_stmt,
+            named_params! { ":list_id": list_id, ":email": email },
+        )
+        .context(error::SqliteExecuteError {
+            statement: delete_user_stmt,
+        })
+}
+
+fn is_member(
+    transaction: &rusqlite::Transaction,
+    list_id: i32,
+    email: &str,
+) -> error::Result<bool> {
+    let get_member_stmt = r"SELECT 1 FROM users WHERE list_id = :list_id AND email = :email";
+    let member: Option<i32> = transaction
+        .query_row_named(
+            get_member_stmt,
+            named_params! { ":list_id": list_id, ":email": email },
+            |row| row.get(0),
+        )
+        .optional()
+        .context(error::SqliteExecuteError {
+            statement: get_member_stmt,
+        })?;
+    Ok(member.is_some())
+}
+
+fn get_mailing_list(
+    transaction: &rusqlite::Transaction,
+    list_name: &str,
+) -> error::Result<types::MailingList> {
+    let get_list_stmt = r"SELECT id, title, email, enabled, language,
+                                 archive_url, help_url, unsubscribe_url,
+                                 fold_case, strip_subaddress
+                          FROM mailing_lists WHERE email = :email";
+    transaction
+        .query_row_named(
+            get_list_stmt,
+            named_params! { ":email": list_name },
+            |row| {
+                Ok(types::MailingList {
+                    id: row.get(0)?,
+                    title: row.get(1)?,
+                    email: row.get(2)?,
+                    enabled: row.get(3)?,
+                    language: row.get(4)?,
+                    archive_url: row.get(5)?,
+                    help_url: row.get(6)?,
+                    unsubscribe_url: row.get(7)?,
+                    fold_case: row.get(8)?,
+                    strip_subaddress: row.get(9)?,
+                })
+            },
+        )
+        .optional()
+        .context(error::SqliteExecuteError {
+            statement: get_list_stmt,
+        })?
+        .ok_or(error::Error::DbMailingListDoesNotExist {
+            list_name: list_name.to_string(),
+        })
+}
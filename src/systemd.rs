@@ -0,0 +1,142 @@
+use crate::error;
+use snafu::ResultExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The first file descriptor passed by the service manager.
+const LISTEN_FDS_START: RawFd = 3;
+
+static SOCKET_ACTIVATED: AtomicBool = AtomicBool::new(false);
+
+/// The listening socket passed by systemd socket activation, if any. The
+/// environment variables are removed so that children do not take it too.
+pub fn listen_socket() -> error::Result<Option<UnixListener>> {
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    let fd = match passed_fd(pid.as_deref(), fds.as_deref(), std::process::id())? {
+        Some(fd) => fd,
+        None => return Ok(None),
+    };
+    check_unix_socket(fd)?;
+    unsafe {
+        libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+    }
+    SOCKET_ACTIVATED.store(true, Ordering::SeqCst);
+    Ok(Some(unsafe { UnixListener::from_raw_fd(fd) }))
+}
+
+/// Whether the socket belongs to the service manager, which then also
+/// removes it.
+pub fn socket_activated() -> bool {
+    SOCKET_ACTIVATED.load(Ordering::SeqCst)
+}
+
+/// The descriptor to listen on if `LISTEN_PID` and `LISTEN_FDS` are meant
+/// for process `own_pid`.
+fn passed_fd(pid: Option<&str>, fds: Option<&str>, own_pid: u32) -> error::Result<Option<RawFd>> {
+    let (pid, fds) = match (pid, fds) {
+        (Some(pid), Some(fds)) => (pid, fds),
+        _ => return Ok(None),
+    };
+    if pid.trim().parse::<u32>().ok() != Some(own_pid) {
+        return Ok(None);
+    }
+    match fds.trim().parse::<u32>() {
+        Ok(0) => Ok(None),
+        Ok(1) => Ok(Some(LISTEN_FDS_START)),
+        _ => Err(error::Error::ListenFdsError {
+            reason: format!("expected one socket, LISTEN_FDS is \"{}\"", fds),
+        }),
+    }
+}
+
+fn check_unix_socket(fd: RawFd) -> error::Result<()> {
+    let mut domain: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_DOMAIN,
+            &mut domain as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result != 0 || domain != libc::AF_UNIX {
+        return Err(error::Error::ListenFdsError {
+            reason: format!("file descriptor {} is not a Unix socket", fd),
+        });
+    }
+    Ok(())
+}
+
+/// Sends `state` to the service manager if it asked for notifications.
+/// Failures are only logged, they must not stop the daemon.
+pub fn notify(state: &str) {
+    if let Some(socket) = std::env::var_os("NOTIFY_SOCKET") {
+        if let Err(err) = notify_socket(&socket.to_string_lossy(), state) {
+            log::warn!("Could not notify service manager: {}", err);
+        }
+    }
+}
+
+/// Sends `state` to the datagram socket `socket`, a leading `@` names an
+/// abstract socket.
+pub fn notify_socket(socket: &str, state: &str) -> error::Result<()> {
+    let context = error::NotifyError { socket };
+    let datagram = UnixDatagram::unbound().context(context)?;
+    match socket.strip_prefix('@') {
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let address = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())
+                .context(context)?;
+            datagram.send_to_addr(state.as_bytes(), &address)
+        }
+        None => datagram.send_to(state.as_bytes(), socket),
+    }
+    .context(context)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn takes_socket_only_for_own_pid() {
+        assert_eq!(super::passed_fd(None, None, 42).unwrap(), None);
+        assert_eq!(super::passed_fd(Some("41"), Some("1"), 42).unwrap(), None);
+        assert_eq!(super::passed_fd(Some("42"), Some("0"), 42).unwrap(), None);
+        assert_eq!(
+            super::passed_fd(Some("42"), Some("1"), 42).unwrap(),
+            Some(super::LISTEN_FDS_START)
+        );
+        assert!(super::passed_fd(Some("42"), Some("2"), 42).is_err());
+    }
+
+    #[test]
+    fn notifies_stand_in_receiver() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify");
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        super::notify_socket(&path.to_string_lossy(), "READY=1\nSTATUS=Serving").unwrap();
+        let mut buffer = [0; 64];
+        let len = receiver.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"READY=1\nSTATUS=Serving");
+
+        let name = format!("simplemm-test-{}", std::process::id());
+        let address =
+            <std::os::unix::net::SocketAddr as std::os::linux::net::SocketAddrExt>::from_abstract_name(
+                name.as_bytes(),
+            )
+            .unwrap();
+        let receiver = UnixDatagram::bind_addr(&address).unwrap();
+        super::notify_socket(&format!("@{}", name), "STOPPING=1").unwrap();
+        let len = receiver.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"STOPPING=1");
+    }
+}
@@ -0,0 +1,254 @@
+use crate::{error, types};
+use snafu::ResultExt;
+use std::path::{Path, PathBuf};
+
+static DEFAULT_LANGUAGE: &str = "EN";
+static LIST_OVERRIDE_DIR: &str = "lists";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Template {
+    Confirmation,
+    Welcome,
+    AlreadySubscribed,
+    Goodbye,
+    Help,
+    Rejection,
+}
+
+pub struct Rendered {
+    pub subject: String,
+    pub body: String,
+}
+
+impl Template {
+    pub fn name(self) -> &'static str {
+        match self {
+            Template::Confirmation => "confirmation",
+            Template::Welcome => "welcome",
+            Template::AlreadySubscribed => "already-subscribed",
+            Template::Goodbye => "goodbye",
+            Template::Help => "help",
+            Template::Rejection => "rejection",
+        }
+    }
+
+    pub fn parse(name: &str) -> error::Result<Template> {
+        match name {
+            "confirmation" => Ok(Template::Confirmation),
+            "welcome" => Ok(Template::Welcome),
+            "already-subscribed" => Ok(Template::AlreadySubscribed),
+            "goodbye" => Ok(Template::Goodbye),
+            "help" => Ok(Template::Help),
+            "rejection" => Ok(Template::Rejection),
+            _ => Err(error::Error::UnknownTemplate {
+                name: name.to_string(),
+            }),
+        }
+    }
+
+    fn builtin(self) -> &'static str {
+        match self {
+            Template::Confirmation => include_str!("../templates/EN/confirmation.hbs"),
+            Template::Welcome => include_str!("../templates/EN/welcome.hbs"),
+            Template::AlreadySubscribed => {
+                include_str!("../templates/EN/already-subscribed.hbs")
+            }
+            Template::Goodbye => include_str!("../templates/EN/goodbye.hbs"),
+            Template::Help => include_str!("../templates/EN/help.hbs"),
+            Template::Rejection => include_str!("../templates/EN/rejection.hbs"),
+        }
+    }
+}
+
+pub fn render(
+    config: &types::Config,
+    list: &types::MailingList,
+    template: Template,
+    data: serde_json::Value,
+) -> error::Result<Rendered> {
+    let source = load(&template_dir(config), list, template)?;
+    let mut data = data;
+    if let Some(object) = data.as_object_mut() {
+        object.insert("list".to_string(), list_data(list));
+    }
+    let mut handlebars = handlebars::Handlebars::new();
+    handlebars.register_escape_fn(handlebars::no_escape);
+    let text = handlebars
+        .render_template(&source, &data)
+        .map_err(Box::new)
+        .context(error::TemplateRenderError {
+            template: template.name(),
+        })?;
+    split_subject(template, &text)
+}
+
+pub fn sample_data(template: Template) -> serde_json::Value {
+    let email = "jane.doe@example.com";
+    match template {
+        Template::Confirmation => serde_json::json!({
+            "subscription": {
+                "email": email,
+                "token": uuid::Uuid::nil().to_string(),
+                "action": types::SubscriptionAction::Subscribe.as_str(),
+                "unsubscribe": false,
+            }
+        }),
+        Template::Welcome | Template::AlreadySubscribed | Template::Goodbye => serde_json::json!({
+            "member": { "email": email }
+        }),
+        Template::Help => serde_json::json!({}),
+        Template::Rejection => serde_json::json!({
+            "post": { "subject": "Hello everybody" },
+            "reason": format!("{} is not a member of this list", email),
+        }),
+    }
+}
+
+fn template_dir(config: &types::Config) -> PathBuf {
+    Path::new(&config.working_dir).join(&config.template_dir)
+}
+
+fn load(dir: &Path, list: &types::MailingList, template: Template) -> error::Result<String> {
+    let file_name = format!("{}.hbs", template.name());
+    let mut candidates = vec![
+        dir.join(&list.language).join(&file_name),
+        dir.join(DEFAULT_LANGUAGE).join(&file_name),
+    ];
+    // lists of former releases may have addresses that leave the directory
+    if is_single_component(&list.email) {
+        candidates.insert(
+            0,
+            dir.join(LIST_OVERRIDE_DIR)
+                .join(&list.email)
+                .join(&file_name),
+        );
+    }
+    for candidate in candidates.iter() {
+        if candidate.is_file() {
+            return std::fs::read_to_string(candidate).context(error::TemplateReadError {
+                path: candidate.to_string_lossy().to_string(),
+            });
+        }
+    }
+    Ok(template.builtin().to_string())
+}
+
+fn is_single_component(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(std::path::Component::Normal(_)), None)
+    )
+}
+
+fn list_data(list: &types::MailingList) -> serde_json::Value {
+    serde_json::json!({
+        "title": list.title,
+        "email": list.email,
+        "language": list.language,
+        "request_address": list.request_address(),
+        "subscribe_address": list.subscribe_address(),
+        "unsubscribe_address": list.unsubscribe_address(),
+        "archive_url": list.archive_url,
+        "help_url": list.help_url,
+    })
+}
+
+fn split_subject(template: Template, text: &str) -> error::Result<Rendered> {
+    let mut lines = text.lines();
+    let subject = lines
+        .next()
+        .and_then(|line| line.strip_prefix("Subject:"))
+        .ok_or(error::Error::TemplateWithoutSubject {
+            template: template.name(),
+        })?;
+    let body: std::vec::Vec<&str> = lines.skip_while(|line| line.trim().is_empty()).collect();
+    Ok(Rendered {
+        subject: subject.trim().to_string(),
+        body: body.join("\n"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Template;
+    use crate::{testing, types};
+
+    fn test_list(language: &str) -> types::MailingList {
+        types::MailingList {
+            language: language.to_string(),
+            ..testing::list()
+        }
+    }
+
+    fn write(path: std::path::PathBuf, content: &str) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+
+    #[test]
+    fn picks_list_override_then_language_then_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let welcome = Template::Welcome;
+        assert!(super::load(dir.path(), &test_list("DE"), welcome)
+            .unwrap()
+            .starts_with("Subject: Welcome to"));
+
+        write(dir.path().join("EN/welcome.hbs"), "Subject: en");
+        assert_eq!(
+            super::load(dir.path(), &test_list("DE"), welcome).unwrap(),
+            "Subject: en"
+        );
+
+        write(dir.path().join("DE/welcome.hbs"), "Subject: de");
+        assert_eq!(
+            super::load(dir.path(), &test_list("DE"), welcome).unwrap(),
+            "Subject: de"
+        );
+
+        write(
+            dir.path().join("lists/gemuese@example.com/welcome.hbs"),
+            "Subject: override",
+        );
+        assert_eq!(
+            super::load(dir.path(), &test_list("DE"), welcome).unwrap(),
+            "Subject: override"
+        );
+
+        // an address that leaves the directory gets no override
+        write(
+            dir.path().join("secret@example.com/welcome.hbs"),
+            "Subject: secret",
+        );
+        let mut escaping = test_list("DE");
+        escaping.email = "../secret@example.com".to_string();
+        assert_eq!(
+            super::load(dir.path(), &escaping, welcome).unwrap(),
+            "Subject: de"
+        );
+    }
+
+    #[test]
+    fn builtin_templates_render_with_sample_data() {
+        let config = testing::config("");
+        for name in &[
+            "confirmation",
+            "welcome",
+            "already-subscribed",
+            "goodbye",
+            "help",
+            "rejection",
+        ] {
+            let template = Template::parse(name).unwrap();
+            let rendered = super::render(
+                &config,
+                &test_list("EN"),
+                template,
+                super::sample_data(template),
+            )
+            .unwrap();
+            assert!(!rendered.subject.is_empty());
+            assert!(!rendered.body.contains("{{"));
+        }
+    }
+}
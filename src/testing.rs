@@ -0,0 +1,36 @@
+//! Fixtures shared by the unit tests.
+
+use crate::types;
+
+const CONFIG: &str = r#"
+db_url = "mysql://localhost/simplemm"
+uid = 1000
+gid = 1000
+pid_file = "/nonexistent/simplemmd.pid"
+working_dir = "/nonexistent"
+socket = "/nonexistent/simplemmd.sock"
+"#;
+
+/// A configuration that never touches the system, `overrides` replaces its
+/// top-level settings and sections.
+pub fn config(overrides: &str) -> types::Config {
+    let mut value: toml::Value = toml::from_str(CONFIG).unwrap();
+    let overrides: toml::value::Table = toml::from_str(overrides).unwrap();
+    value.as_table_mut().unwrap().extend(overrides);
+    value.try_into().unwrap()
+}
+
+pub fn list() -> types::MailingList {
+    types::MailingList {
+        id: 1,
+        title: "Gemüse".to_string(),
+        email: "gemuese@example.com".to_string(),
+        enabled: true,
+        language: "EN".to_string(),
+        archive_url: None,
+        help_url: None,
+        unsubscribe_url: None,
+        fold_case: true,
+        strip_subaddress: false,
+    }
+}
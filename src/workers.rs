@@ -0,0 +1,105 @@
+use crate::{error, shutdown, stats, types};
+use snafu::ResultExt;
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// A fixed number of threads serving connections from a bounded queue.
+/// Queued connections count as in flight for the shutdown, the handler gets
+/// the `InFlight` of its connection.
+pub struct WorkerPool {
+    sender: mpsc::SyncSender<(UnixStream, shutdown::InFlight<'static>, stats::Gauge)>,
+}
+
+impl WorkerPool {
+    pub fn start<F>(config: &types::WorkerConfig, handler: F) -> error::Result<WorkerPool>
+    where
+        F: Fn(UnixStream, shutdown::InFlight<'static>) + Send + Sync + 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel(config.queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let handler = Arc::new(handler);
+        let timeouts = (
+            Duration::from_secs(config.read_timeout_seconds),
+            Duration::from_secs(config.write_timeout_seconds),
+        );
+        for number in 0..config.threads {
+            let receiver = receiver.clone();
+            let handler = handler.clone();
+            std::thread::Builder::new()
+                .name(format!("worker-{}", number))
+                .spawn(move || loop {
+                    let (stream, queued, _) = match receiver
+                        .lock()
+                        .unwrap_or_else(|poisoned| poisoned.into_inner())
+                        .recv()
+                    {
+                        Ok(connection) => connection,
+                        Err(_) => break,
+                    };
+                    if let Err(err) = set_timeouts(&stream, timeouts) {
+                        log::warn!("Could not set timeouts of connection: {}", err);
+                        continue;
+                    }
+                    let _busy = stats::worker_busy();
+                    handler(stream, queued);
+                })
+                .context(error::WorkerSpawnError {})?;
+        }
+        Ok(WorkerPool { sender })
+    }
+
+    /// Queues the connection, hands it back if the queue is full.
+    pub fn dispatch(&self, stream: UnixStream) -> Result<(), UnixStream> {
+        match self
+            .sender
+            .try_send((stream, shutdown::track(), stats::queued()))
+        {
+            Ok(()) => Ok(()),
+            Err(mpsc::TrySendError::Full((stream, _, _)))
+            | Err(mpsc::TrySendError::Disconnected((stream, _, _))) => Err(stream),
+        }
+    }
+}
+
+fn set_timeouts(stream: &UnixStream, (read, write): (Duration, Duration)) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(read).filter(|timeout| !timeout.is_zero()))?;
+    stream.set_write_timeout(Some(write).filter(|timeout| !timeout.is_zero()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types;
+    use std::os::unix::net::UnixStream;
+    use std::sync::mpsc;
+
+    #[test]
+    fn hands_back_connections_when_queue_is_full() {
+        let config = types::WorkerConfig {
+            threads: 1,
+            queue_size: 1,
+            ..Default::default()
+        };
+        let (started, wait_started) = mpsc::channel();
+        let (release, wait_release) = mpsc::channel::<()>();
+        let wait_release = std::sync::Mutex::new(wait_release);
+        let pool = super::WorkerPool::start(&config, move |_stream, _queued| {
+            started.send(()).unwrap();
+            let _ = wait_release.lock().unwrap().recv();
+        })
+        .unwrap();
+
+        let streams: std::vec::Vec<UnixStream> =
+            (0..3).map(|_| UnixStream::pair().unwrap().0).collect();
+        let mut streams = streams.into_iter();
+        assert!(pool.dispatch(streams.next().unwrap()).is_ok());
+        wait_started.recv().unwrap();
+        assert!(pool.dispatch(streams.next().unwrap()).is_ok());
+        assert!(pool.dispatch(streams.next().unwrap()).is_err());
+
+        release.send(()).unwrap();
+        wait_started.recv().unwrap();
+        release.send(()).unwrap();
+    }
+}